@@ -1,8 +1,10 @@
+use std::fmt;
+
 use crate::{
     instruction_formats::{
-        get_instruction_formats, InstructionBitsUsage, InstructionFormat, OperationType
+        opcode_formats, InstructionBitsUsage, InstructionFormat, OpcodeEntry, OperationType
     },
-    memory::{Memory, SegmentedAccess},
+    memory::{ByteSource, SegmentedAccess},
     register::{RegisterAccess, RegisterIndex, EffectiveAddressExpression, EffectiveAddressBase},
 };
 
@@ -10,25 +12,81 @@ use crate::{
 pub struct InstructionFlag;
 
 impl InstructionFlag {
-    pub const LOCK: u32 = 1 << 0;
-    pub const REP: u32 = 1 << 1;
-    pub const SEGMENT: u32 = 1 << 2;
-    pub const WIDE: u32 = 1 << 3;
+    pub const WIDE: u32 = 1 << 0;
+    pub const FAR: u32 = 1 << 1;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepPrefix {
+    /// F3: REP, or REPE/REPZ on CMPS and SCAS
+    Rep,
+    /// F2: REPNE/REPNZ
+    Repne,
+}
+
+/// Prefix bytes that preceded an instruction, folded into it by the decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Prefixes {
+    pub lock: bool,
+    pub rep: Option<RepPrefix>,
+    pub segment: Option<RegisterIndex>,
+}
+
+/// Why the bytes at an address are not an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorReason {
+    /// No instruction starts with this opcode byte.
+    UnknownOpcode,
+    /// The byte source ended partway through the instruction.
+    Truncated,
+    /// The opcode is known but the byte after it selects no instruction, such
+    /// as reg field 7 of an 0xFE.
+    InvalidModRm,
+}
+
+/// An undecodable instruction. `bytes` runs from the first prefix through the
+/// byte that could not be decoded, or to the end of the source if truncated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub address: u32,
+    pub bytes: Vec<u8>,
+    pub reason: DecodeErrorReason,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.reason {
+            DecodeErrorReason::UnknownOpcode => "unknown opcode",
+            DecodeErrorReason::Truncated => "truncated instruction",
+            DecodeErrorReason::InvalidModRm => "invalid ModRM byte for opcode",
+        };
+        write!(f, "{} at 0x{:05x}:", reason, self.address)?;
+        for byte in &self.bytes {
+            write!(f, " {:02x}", byte)?;
+        }
+        Ok(())
+    }
 }
 
+impl std::error::Error for DecodeError {}
+
+/// Why a format did not decode the bytes it was tried against.
 #[derive(Debug, Clone, Copy, PartialEq)]
+enum FormatMismatch {
+    Literal,
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum Operand {
+    #[default]
     None,
     Register(RegisterAccess),
     Memory(EffectiveAddressExpression),
     Immediate(u32),
     RelativeImmediate(i32),
-}
-
-impl Default for Operand {
-    fn default() -> Self {
-        Operand::None
-    }
+    /// Direct intersegment target of `call`/`jmp segment:offset`.
+    FarPointer { segment: u16, offset: u16 },
 }
 
 #[derive(Debug, Clone)]
@@ -38,6 +96,7 @@ pub struct Instruction {
     pub op: OperationType,
     pub flags: u32,
     pub operands: [Operand; 2],
+    pub prefixes: Prefixes,
 }
 
 impl Default for Instruction {
@@ -48,42 +107,7 @@ impl Default for Instruction {
             op: OperationType::None,
             flags: 0,
             operands: [Operand::default(); 2],
-        }
-    }
-}
-
-pub struct DisasmContext {
-    pub default_segment: RegisterIndex,
-    pub additional_flags: u32,
-}
-
-impl DisasmContext {
-    pub fn new() -> Self {
-        Self {
-            default_segment: RegisterIndex::DS,
-            additional_flags: 0,
-        }
-    }
-
-    pub fn update(&mut self, instruction: &Instruction) {
-        match instruction.op {
-            OperationType::Lock => {
-                self.additional_flags |= InstructionFlag::LOCK;
-            }
-            OperationType::Rep => {
-                self.additional_flags |= InstructionFlag::REP;
-            }
-            OperationType::Segment => {
-                self.additional_flags |= InstructionFlag::SEGMENT;
-                self.default_segment = match instruction.operands[1] {
-                    Operand::Register(reg) => reg.index,
-                    _ => unreachable!(),
-                };
-            }
-            _ => {
-                self.additional_flags = 0;
-                self.default_segment = RegisterIndex::DS
-            }
+            prefixes: Prefixes::default(),
         }
     }
 }
@@ -112,34 +136,46 @@ fn get_reg_operand(intel_reg_index: u32, wide: bool) -> Operand {
     )
 }
 
-fn parse_data_value(memory: &Memory, access: &mut SegmentedAccess, exists: bool, wide: bool, sign_extended: bool) -> u32 {
+fn next_byte<S: ByteSource + ?Sized>(source: &S, access: &mut SegmentedAccess) -> Result<u8, FormatMismatch> {
+    let byte = source.read_byte(access.get_absolute_address(0)).ok_or(FormatMismatch::Truncated)?;
+    access.segment_offset = access.segment_offset.wrapping_add(1);
+    Ok(byte)
+}
+
+fn parse_data_value<S: ByteSource + ?Sized>(
+    source: &S,
+    access: &mut SegmentedAccess,
+    exists: bool,
+    wide: bool,
+    sign_extended: bool,
+) -> Result<u32, FormatMismatch> {
     if !exists {
-        return 0;
+        return Ok(0);
     }
 
-    let result = if wide {
-        let d0 = memory.read(access.get_absolute_address(0));
-        let d1 = memory.read(access.get_absolute_address(1));
-        access.segment_offset += 2;
-        ((d1 as u32) << 8) | (d0 as u32)
+    if wide {
+        let d0 = next_byte(source, access)?;
+        let d1 = next_byte(source, access)?;
+        Ok(((d1 as u32) << 8) | (d0 as u32))
     } else {
-        let d = memory.read(access.get_absolute_address(0));
-        access.segment_offset += 1;
+        let d = next_byte(source, access)?;
         if sign_extended {
-            (d as i8) as u32
+            Ok((d as i8) as u32)
         } else {
-            d as u32
+            Ok(d as u32)
         }
-    };
-
-    result
+    }
 }
 
-fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memory, mut at: SegmentedAccess) -> Option<Instruction> {
+fn try_decode<S: ByteSource + ?Sized>(
+    prefixes: &Prefixes,
+    format: &InstructionFormat,
+    source: &S,
+    mut at: SegmentedAccess,
+) -> Result<Instruction, FormatMismatch> {
     let mut instruction = Instruction::default();
-    let mut bits = [0u32; 18];
+    let mut bits = [0u32; 19];
     let mut has_bits = 0u32;
-    let mut valid = true;
 
     let starting_address = at.get_absolute_address(0);
  
@@ -156,13 +192,11 @@ fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memo
         if test_bits.bit_count != 0 {
             if bits_pending_count == 0 {
                 bits_pending_count = 8;
-                bits_pending = memory.read(at.get_absolute_address(0));
-                at.segment_offset += 1;
+                bits_pending = next_byte(source, &mut at)?;
             }
 
             if test_bits.bit_count > bits_pending_count {
-                valid = false;
-                break;
+                return Err(FormatMismatch::Literal);
             }
 
             bits_pending_count -= test_bits.bit_count;
@@ -172,7 +206,10 @@ fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memo
         }
 
         if test_bits.usage == InstructionBitsUsage::Literal {
-            valid = valid && (read_bits == test_bits.value as u32);
+            // Stop before reading bytes that belong to some other format
+            if read_bits != test_bits.value as u32 {
+                return Err(FormatMismatch::Literal);
+            }
         } else {
             let usage_index = test_bits.usage as usize;
             bits[usage_index] |= read_bits << test_bits.shift;
@@ -180,10 +217,6 @@ fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memo
         }
     }
 
-    if !valid {
-        return None;
-    }
-
     // Decode the instruction using the parsed bits
     let mod_val = bits[InstructionBitsUsage::Mod as usize];
     let rm = bits[InstructionBitsUsage::Rm as usize];
@@ -198,11 +231,16 @@ fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memo
                            mod_val == 0b10 || has_direct_address;
     let data_is_w = bits[InstructionBitsUsage::WMakesDataW as usize] != 0 && !s && w;
 
-    bits[InstructionBitsUsage::Disp as usize] = parse_data_value(memory, &mut at, has_displacement, displacement_is_w, !displacement_is_w);
-    bits[InstructionBitsUsage::Data as usize] = parse_data_value(memory, &mut at, bits[InstructionBitsUsage::HasData as usize] != 0, data_is_w, s);
+    bits[InstructionBitsUsage::Disp as usize] = parse_data_value(source, &mut at, has_displacement, displacement_is_w, !displacement_is_w)?;
+    // ESC carries its opcode in the instruction bits rather than trailing data
+    let has_data = bits[InstructionBitsUsage::HasData as usize] != 0;
+    if has_data {
+        bits[InstructionBitsUsage::Data as usize] = parse_data_value(source, &mut at, true, data_is_w, s)?;
+    }
+    let has_data = has_data || (has_bits & (1 << (InstructionBitsUsage::Data as usize))) != 0;
 
     instruction.op = format.op;
-    instruction.flags = context.additional_flags;
+    instruction.prefixes = *prefixes;
     instruction.address = starting_address;
     instruction.size = at.get_absolute_address(0) - starting_address;
     
@@ -210,6 +248,16 @@ fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memo
         instruction.flags |= InstructionFlag::WIDE;
     }
 
+    if bits[InstructionBitsUsage::Far as usize] != 0 {
+        instruction.flags |= InstructionFlag::FAR;
+    }
+
+    // F2 (Z clear) is REPNE/REPNZ, F3 (Z set) is REP/REPE/REPZ
+    if (has_bits & (1 << (InstructionBitsUsage::Z as usize))) != 0 {
+        let repne = bits[InstructionBitsUsage::Z as usize] == 0;
+        instruction.prefixes.rep = Some(if repne { RepPrefix::Repne } else { RepPrefix::Rep });
+    }
+
     let displacement = bits[InstructionBitsUsage::Disp as usize] as i16;
 
     let reg_operand_index = if d { 0 } else { 1 };
@@ -260,7 +308,7 @@ fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memo
 
             instruction.operands[mod_operand_index] = Operand::Memory(
                 EffectiveAddressExpression {
-                    segment: context.default_segment,
+                    segment: prefixes.segment.unwrap_or(base.default_segment()),
                     base,
                     displacement: displacement as i32,
                 }
@@ -275,7 +323,13 @@ fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memo
         instruction.operands[last_operand_index] = Operand::RelativeImmediate(displacement as i32 + instruction.size as i32);
     }
 
-    if bits[InstructionBitsUsage::HasData as usize] != 0 {
+    if bits[InstructionBitsUsage::Far as usize] != 0 && has_data {
+        // The offset word comes first, then the segment
+        instruction.operands[0] = Operand::FarPointer {
+            segment: bits[InstructionBitsUsage::Data as usize] as u16,
+            offset: displacement as u16,
+        };
+    } else if has_data {
         instruction.operands[last_operand_index] = Operand::Immediate(bits[InstructionBitsUsage::Data as usize]);
     }
 
@@ -293,18 +347,180 @@ fn try_decode(context: &DisasmContext, format: &InstructionFormat, memory: &Memo
         }
     }
 
-    Some(instruction)
+    Ok(instruction)
 }
 
-pub fn decode_instruction(context: &DisasmContext, memory: &Memory, at: &mut SegmentedAccess) -> Instruction {
-    let instruction_formats = get_instruction_formats();
-    
-    for format in &instruction_formats {
-        if let Some(instruction) = try_decode(context, format, memory, *at) {
-            return instruction;
+/// Up to `count` bytes starting at `at`, fewer if the source ends first.
+fn read_bytes<S: ByteSource + ?Sized>(source: &S, at: SegmentedAccess, count: u32) -> Vec<u8> {
+    (0..count)
+        .map_while(|i| source.read_byte(at.get_absolute_address(i as u16)))
+        .collect()
+}
+
+/// Decodes the instruction at `at`, including any LOCK, REP and segment
+/// override prefixes in front of it. The address and size cover the prefixes.
+pub fn decode_instruction<S: ByteSource + ?Sized>(
+    source: &S,
+    at: &SegmentedAccess,
+) -> Result<Instruction, DecodeError> {
+    let starting_address = at.get_absolute_address(0);
+    let mut prefixes = Prefixes::default();
+    let mut prefix_size = 0u32;
+    let mut next = *at;
+
+    let error = |reason, count| DecodeError {
+        address: starting_address,
+        bytes: read_bytes(source, *at, count),
+        reason,
+    };
+    // Prefixes plus the longest 8086 instruction
+    let truncated = |prefix_size| error(DecodeErrorReason::Truncated, prefix_size + 6);
+
+    // The 8086 accepts any number of prefixes, but not more than fit in a segment
+    while prefix_size <= u16::MAX as u32 {
+        let Some(opcode) = source.read_byte(next.get_absolute_address(0)) else {
+            return Err(truncated(prefix_size));
+        };
+        let formats = match opcode_formats(opcode) {
+            OpcodeEntry::Formats(formats) if formats.is_empty() => {
+                return Err(error(DecodeErrorReason::UnknownOpcode, prefix_size + 1));
+            }
+            OpcodeEntry::Formats(formats) => formats,
+            OpcodeEntry::ByReg(by_reg) => {
+                let Some(modrm) = source.read_byte(next.get_absolute_address(1)) else {
+                    return Err(truncated(prefix_size));
+                };
+                &by_reg[(modrm >> 3 & 0b111) as usize]
+            }
+        };
+
+        // A format that ran out of bytes only matters if no other one matches
+        let mut ran_out = false;
+        let decoded = formats.iter().find_map(|format| match try_decode(&prefixes, format, source, next) {
+            Ok(instruction) => Some(instruction),
+            Err(mismatch) => {
+                ran_out |= mismatch == FormatMismatch::Truncated;
+                None
+            }
+        });
+
+        let Some(mut instruction) = decoded else {
+            if ran_out {
+                return Err(truncated(prefix_size));
+            }
+            return Err(error(DecodeErrorReason::InvalidModRm, prefix_size + 2));
+        };
+
+        match instruction.op {
+            OperationType::Lock => prefixes.lock = true,
+            OperationType::Rep => prefixes.rep = instruction.prefixes.rep,
+            OperationType::Segment => {
+                prefixes.segment = match instruction.operands[1] {
+                    Operand::Register(reg) => Some(reg.index),
+                    _ => unreachable!(),
+                };
+            }
+            _ => {
+                instruction.address = starting_address;
+                instruction.size += prefix_size;
+                // Relative targets are measured from the first prefix byte
+                for operand in &mut instruction.operands {
+                    if let Operand::RelativeImmediate(offset) = operand {
+                        *offset += prefix_size as i32;
+                    }
+                }
+                return Ok(instruction);
+            }
         }
+
+        prefix_size += instruction.size;
+        next.segment_offset = next.segment_offset.wrapping_add(instruction.size as u16);
     }
-    
-    Instruction::default()
+
+    // Nothing but prefixes up to the end of the segment
+    Err(error(DecodeErrorReason::UnknownOpcode, prefix_size))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction_formats::get_instruction_formats;
+
+    /// First format that decodes `bytes`, or whether any of them ran out of
+    /// bytes trying.
+    fn first_match<'a>(formats: impl IntoIterator<Item = &'a InstructionFormat>, bytes: &[u8]) -> Result<String, bool> {
+        let mut ran_out = false;
+        let at = SegmentedAccess { segment_base: 0, segment_offset: 0 };
+        for format in formats {
+            match try_decode(&Prefixes::default(), format, bytes, at) {
+                Ok(instruction) => return Ok(format!("{instruction:?}")),
+                Err(mismatch) => ran_out |= mismatch == FormatMismatch::Truncated,
+            }
+        }
+        Err(ran_out)
+    }
+
+    #[test]
+    fn opcode_table_agrees_with_a_linear_scan() {
+        let formats = get_instruction_formats();
+        for first in 0..=255u8 {
+            for second in 0..=255u8 {
+                let bytes = [first, second, 0x5A, 0x69, 0x5A, 0xDB];
+                let candidates = match opcode_formats(first) {
+                    OpcodeEntry::Formats(formats) => formats,
+                    OpcodeEntry::ByReg(by_reg) => &by_reg[(second >> 3 & 0b111) as usize],
+                };
+                assert_eq!(first_match(candidates, &bytes), first_match(&formats, &bytes), "{bytes:02x?}");
+            }
+        }
+    }
+
+    #[test]
+    fn direct_far_transfers_carry_segment_and_offset() {
+        let at = SegmentedAccess::default();
+        for (code, op) in [(0x9A, OperationType::Call), (0xEA, OperationType::Jmp)] {
+            let instruction = decode_instruction(&[code, 0x78, 0x56, 0x34, 0x12][..], &at).unwrap();
+            assert_eq!((instruction.op, instruction.size), (op, 5));
+            assert_eq!(instruction.operands[0], Operand::FarPointer { segment: 0x1234, offset: 0x5678 });
+        }
+    }
+
+    #[test]
+    fn prefixes_fold_into_the_instruction_that_follows() {
+        use crate::register::RegisterIndex::{CS, ES, SS};
+        let at = SegmentedAccess::default();
+        let prefixes = |lock, rep, segment| Prefixes { lock, rep, segment };
+        for (code, op, expected) in [
+            (&[0xF3, 0xA4][..], OperationType::Movs, prefixes(false, Some(RepPrefix::Rep), None)),
+            (&[0xF2, 0xAE], OperationType::Scas, prefixes(false, Some(RepPrefix::Repne), None)),
+            (&[0xF0, 0x87, 0x07], OperationType::Xchg, prefixes(true, None, None)),
+            (&[0x26, 0x8B, 0x07], OperationType::Mov, prefixes(false, None, Some(ES))),
+            (&[0x2E, 0xF3, 0xA4], OperationType::Movs, prefixes(false, Some(RepPrefix::Rep), Some(CS))),
+            (&[0xF0, 0x2E, 0xF3, 0x36, 0xA5], OperationType::Movs, prefixes(true, Some(RepPrefix::Rep), Some(SS))),
+        ] {
+            let instruction = decode_instruction(code, &at).unwrap();
+            assert_eq!((instruction.op, instruction.size as usize), (op, code.len()), "{code:02x?}");
+            assert_eq!(instruction.prefixes, expected, "{code:02x?}");
+        }
+
+        // Overrides reach memory operands, and jumps count the prefix in their length
+        let instruction = decode_instruction(&[0x26, 0x8B, 0x07][..], &at).unwrap();
+        assert!(matches!(instruction.operands[1], Operand::Memory(EffectiveAddressExpression { segment: ES, .. })));
+        let instruction = decode_instruction(&[0x2E, 0xEB, 0x00][..], &at).unwrap();
+        assert_eq!(instruction.operands[0], Operand::RelativeImmediate(3));
+    }
+
+    #[test]
+    fn slices_that_end_mid_instruction_report_truncated() {
+        let truncated =
+            |address, bytes: &[u8]| DecodeError { address, bytes: bytes.to_vec(), reason: DecodeErrorReason::Truncated };
+        let start = SegmentedAccess::default();
+        assert_eq!(decode_instruction(&[0xB8, 0x34][..], &start).unwrap_err(), truncated(0, &[0xB8, 0x34]));
+        assert_eq!(decode_instruction(&[0xF3][..], &start).unwrap_err(), truncated(0, &[0xF3]));
+        assert_eq!(decode_instruction(&[][..], &start).unwrap_err(), truncated(0, &[]));
+
+        let second = SegmentedAccess { segment_base: 0, segment_offset: 1 };
+        assert_eq!(decode_instruction(&[0x90, 0xE8, 0x00][..], &second).unwrap_err(), truncated(1, &[0xE8, 0x00]));
+        assert_eq!(decode_instruction(&[0x90, 0xE8, 0x00, 0x00][..], &second).unwrap().size, 3);
+    }
+}
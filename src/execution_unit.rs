@@ -1,73 +1,871 @@
-use std::io;
-
 use crate::{
-    memory::Memory,
-    register::RegisterFile,
+    fpu::Fpu,
+    memory::{Memory, SegmentedAccess},
+    port_bus::PortBus,
+    register::{
+        RegisterFile, RegisterAccess, RegisterIndex, EffectiveAddressExpression, EffectiveAddressBase, Flag,
+        FLAGS_WRITABLE, FLAGS_SAHF_WRITABLE,
+    },
     instruction_formats::OperationType,
-    decoder::{Instruction, Operand},
+    decoder::{Instruction, InstructionFlag, Operand, RepPrefix},
 };
 
+/// Interrupt type raised by DIV, IDIV and AAM on a zero divisor or quotient overflow.
+const DIVIDE_ERROR: u8 = 0;
+/// Interrupt type raised after each instruction while TF is set.
+pub const SINGLE_STEP: u8 = 1;
+/// Interrupt type raised by the one-byte INT3.
+const BREAKPOINT: u8 = 3;
+/// Interrupt type raised by INTO when OF is set.
+const OVERFLOW: u8 = 4;
+/// The PC wires the 8087's interrupt output to NMI.
+const NMI: u8 = 2;
+
+fn word_register(index: RegisterIndex) -> RegisterAccess {
+    RegisterAccess {
+        index,
+        offset: 0,
+        count: 2,
+    }
+}
+
+/// Resolves an effective address expression to a segment:offset pair.
+/// Offset arithmetic wraps at 64K like the 8086's address adder.
+pub fn effective_address(reg_file: &RegisterFile, address: &EffectiveAddressExpression) -> SegmentedAccess {
+    let base = match address.base {
+        EffectiveAddressBase::Direct => 0,
+        EffectiveAddressBase::BxSi => reg_file.bx.wrapping_add(reg_file.si),
+        EffectiveAddressBase::BxDi => reg_file.bx.wrapping_add(reg_file.di),
+        EffectiveAddressBase::BpSi => reg_file.bp.wrapping_add(reg_file.si),
+        EffectiveAddressBase::BpDi => reg_file.bp.wrapping_add(reg_file.di),
+        EffectiveAddressBase::Si => reg_file.si,
+        EffectiveAddressBase::Di => reg_file.di,
+        EffectiveAddressBase::Bp => reg_file.bp,
+        EffectiveAddressBase::Bx => reg_file.bx,
+    };
+
+    SegmentedAccess {
+        segment_base: reg_file.get_register_value(&word_register(address.segment)),
+        segment_offset: base.wrapping_add(address.displacement as u16),
+    }
+}
+
+fn read_operand(memory: &Memory, reg_file: &RegisterFile, operand: &Operand, wide: bool) -> u16 {
+    let value = match operand {
+        Operand::Register(reg) => reg_file.get_register_value(reg),
+        Operand::Memory(address) => memory.read_value(&effective_address(reg_file, address), wide),
+        Operand::Immediate(v) => (*v & 0xFFFF) as u16,
+        _ => 0,
+    };
+
+    if wide { value } else { value & 0xFF }
+}
+
+fn write_operand(memory: &mut Memory, reg_file: &mut RegisterFile, operand: &Operand, wide: bool, value: u16) {
+    match operand {
+        Operand::Register(reg) => reg_file.update_register(reg, value),
+        Operand::Memory(address) => {
+            let at = effective_address(reg_file, address);
+            memory.write_value(&at, wide, value);
+        }
+        _ => {}
+    }
+}
+
+fn push_word(memory: &mut Memory, reg_file: &mut RegisterFile, value: u16) {
+    reg_file.sp = reg_file.sp.wrapping_sub(2);
+    let at = SegmentedAccess {
+        segment_base: reg_file.ss,
+        segment_offset: reg_file.sp,
+    };
+    memory.write_value(&at, true, value);
+}
+
+fn pop_word(memory: &Memory, reg_file: &mut RegisterFile) -> u16 {
+    let at = SegmentedAccess {
+        segment_base: reg_file.ss,
+        segment_offset: reg_file.sp,
+    };
+    reg_file.sp = reg_file.sp.wrapping_add(2);
+    memory.read_value(&at, true)
+}
+
+/// Resolves the offset and segment of a far pointer, either given directly in
+/// the instruction or read from memory as by LDS, LES and indirect far CALL
+/// or JMP. The 8086 has no meaningful register form for these.
+fn read_far_pointer(memory: &Memory, reg_file: &RegisterFile, operand: &Operand) -> Option<(u16, u16)> {
+    match operand {
+        Operand::FarPointer { segment, offset } => Some((*offset, *segment)),
+        Operand::Memory(address) => {
+            let at = effective_address(reg_file, address);
+            let offset = memory.read_value(&at, true);
+            let segment = memory.read_value(&SegmentedAccess { segment_offset: at.segment_offset.wrapping_add(2), ..at }, true);
+            Some((offset, segment))
+        }
+        _ => None,
+    }
+}
+
+/// An interrupt whose IVT entry is null. The 8086 would jump to 0000:0000
+/// and run whatever is there, so it is refused instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnhandledInterrupt(pub u8);
+
+/// Real-mode interrupt dispatch: pushes FLAGS, CS and IP, clears IF and TF and
+/// loads CS:IP from the interrupt vector table at 0000:0000. Software
+/// interrupts, CPU exceptions and IRQs all come through here. A null vector
+/// leaves every register and the stack untouched.
+pub fn interrupt(memory: &mut Memory, reg_file: &mut RegisterFile, vector: u8) -> Result<(), UnhandledInterrupt> {
+    let entry = SegmentedAccess {
+        segment_base: 0,
+        segment_offset: (vector as u16) * 4,
+    };
+    let ip = memory.read_value(&entry, true);
+    let cs = memory.read_value(&SegmentedAccess { segment_offset: entry.segment_offset + 2, ..entry }, true);
+    if cs == 0 && ip == 0 {
+        return Err(UnhandledInterrupt(vector));
+    }
+
+    push_word(memory, reg_file, reg_file.flags);
+    push_word(memory, reg_file, reg_file.cs);
+    push_word(memory, reg_file, reg_file.ip);
+    reg_file.set_flag(Flag::Interrupt, false);
+    reg_file.set_flag(Flag::Trap, false);
+    reg_file.ip = ip;
+    reg_file.cs = cs;
+    Ok(())
+}
+
+/// Performs one iteration of a string instruction. The source is DS:SI unless
+/// overridden, the destination is always ES:DI, and both step by the operand
+/// size in the direction selected by DF.
+fn string_operation(
+    op: OperationType,
+    memory: &mut Memory,
+    reg_file: &mut RegisterFile,
+    source_segment: RegisterIndex,
+    wide: bool,
+) {
+    let size: u16 = if wide { 2 } else { 1 };
+    let delta = if reg_file.get_flag(Flag::Direction) { size.wrapping_neg() } else { size };
+
+    let source = SegmentedAccess {
+        segment_base: reg_file.get_register_value(&word_register(source_segment)),
+        segment_offset: reg_file.si,
+    };
+    let destination = SegmentedAccess {
+        segment_base: reg_file.es,
+        segment_offset: reg_file.di,
+    };
+    let accumulator = RegisterAccess {
+        index: RegisterIndex::A,
+        offset: 0,
+        count: if wide { 2 } else { 1 },
+    };
+
+    match op {
+        OperationType::Movs => {
+            let val = memory.read_value(&source, wide);
+            memory.write_value(&destination, wide, val);
+        }
+        OperationType::Cmps => {
+            let val1 = memory.read_value(&source, wide);
+            let val2 = memory.read_value(&destination, wide);
+            let _ = reg_file.alu_sub(val1, val2, wide);
+        }
+        OperationType::Scas => {
+            let val1 = reg_file.get_register_value(&accumulator);
+            let val2 = memory.read_value(&destination, wide);
+            let _ = reg_file.alu_sub(val1, val2, wide);
+        }
+        OperationType::Lods => {
+            let val = memory.read_value(&source, wide);
+            reg_file.update_register(&accumulator, val);
+        }
+        OperationType::Stos => {
+            let val = reg_file.get_register_value(&accumulator);
+            memory.write_value(&destination, wide, val);
+        }
+        _ => {}
+    }
+
+    if matches!(op, OperationType::Movs | OperationType::Cmps | OperationType::Lods) {
+        reg_file.si = reg_file.si.wrapping_add(delta);
+    }
+    if matches!(op, OperationType::Movs | OperationType::Cmps | OperationType::Scas | OperationType::Stos) {
+        reg_file.di = reg_file.di.wrapping_add(delta);
+    }
+}
+
+/// Single-operand forms carry their operand in either slot depending on the
+/// implied D bit of the format, so pick whichever one is present.
+fn unary_operand(instruction: &Instruction) -> &Operand {
+    match &instruction.operands {
+        [Operand::None, operand] => operand,
+        [operand, _] => operand,
+    }
+}
+
+/// Evaluates the condition of a conditional jump against the current flags.
+fn jump_condition(op: OperationType, reg_file: &RegisterFile) -> bool {
+    let cf = reg_file.get_flag(Flag::Carry);
+    let pf = reg_file.get_flag(Flag::Parity);
+    let zf = reg_file.get_flag(Flag::Zero);
+    let sf = reg_file.get_flag(Flag::Sign);
+    let of = reg_file.get_flag(Flag::Overflow);
+
+    match op {
+        OperationType::Je => zf,
+        OperationType::Jne => !zf,
+        OperationType::Jl => sf != of,
+        OperationType::Jnl => sf == of,
+        OperationType::Jle => zf || (sf != of),
+        OperationType::Jg => !zf && (sf == of),
+        OperationType::Jb => cf,
+        OperationType::Jnb => !cf,
+        OperationType::Jbe => cf || zf,
+        OperationType::Ja => !cf && !zf,
+        OperationType::Jp => pf,
+        OperationType::Jnp => !pf,
+        OperationType::Jo => of,
+        OperationType::Jno => !of,
+        OperationType::Js => sf,
+        OperationType::Jns => !sf,
+        _ => false,
+    }
+}
+
+/// Transfers control to a relative target. The decoder encodes the target
+/// relative to the start of the instruction, while IP already points past it.
+fn jump_relative(instruction: &Instruction, reg_file: &mut RegisterFile, offset: i32) {
+    reg_file.ip = reg_file.ip
+        .wrapping_sub(instruction.size as u16)
+        .wrapping_add(offset as u16);
+}
+
+/// Executes `instruction`, whose CS:IP has already been advanced past it.
+/// Fails if it raised an interrupt whose IVT entry is null.
 pub fn execute_instruction(
     instruction: &Instruction,
-    _memory: &Memory,
+    memory: &mut Memory,
     reg_file: &mut RegisterFile,
-) -> io::Result<()> {
+    ports: &mut PortBus,
+    fpu: Option<&mut Fpu>,
+) -> Result<(), UnhandledInterrupt> {
+    let wide = (instruction.flags & InstructionFlag::WIDE) != 0;
+    let far = (instruction.flags & InstructionFlag::FAR) != 0;
+    // The trap is armed by TF as it stood before the instruction, so POPF or
+    // IRET setting TF only takes effect after the following instruction
+    let trap = reg_file.get_flag(Flag::Trap);
+    let [dst, src] = &instruction.operands;
+    let mut raised = Ok(());
+
     match instruction.op {
         OperationType::Mov => {
-            if let Operand::Register(dst) = &instruction.operands[0] {
-                let val = match &instruction.operands[1] {
-                    Operand::Immediate(v) => (*v & 0xFFFF) as u16,
-                    Operand::Register(src) => reg_file.get_register_value(src),
-                    Operand::Memory(_) => 0, // TODO: memory read
-                    _ => 0,
-                };
-                reg_file.update_register(dst, val);
-            }
+            let val = read_operand(memory, reg_file, src, wide);
+            write_operand(memory, reg_file, dst, wide, val);
         }
 
         OperationType::Add => {
-            if let Operand::Register(dst) = &instruction.operands[0] {
-                let val1 = reg_file.get_register_value(dst);
-                let val2 = match &instruction.operands[1] {
-                    Operand::Immediate(v) => (*v & 0xFFFF) as u16,
-                    Operand::Register(src) => reg_file.get_register_value(src),
-                    Operand::Memory(_) => 0,
-                    _ => 0,
-                };
-                let res = reg_file.alu_add(val1, val2);
-                reg_file.update_register(dst, res);
-            }
+            let val1 = read_operand(memory, reg_file, dst, wide);
+            let val2 = read_operand(memory, reg_file, src, wide);
+            let res = reg_file.alu_add(val1, val2, wide);
+            write_operand(memory, reg_file, dst, wide, res);
         }
 
         OperationType::Sub => {
-            if let Operand::Register(dst) = &instruction.operands[0] {
-                let val1 = reg_file.get_register_value(dst);
-                let val2 = match &instruction.operands[1] {
-                    Operand::Immediate(v) => (*v & 0xFFFF) as u16,
-                    Operand::Register(src) => reg_file.get_register_value(src),
-                    Operand::Memory(_) => 0,
-                    _ => 0,
-                };
-                let res = reg_file.alu_sub(val1, val2);
-                reg_file.update_register(dst, res);
+            let val1 = read_operand(memory, reg_file, dst, wide);
+            let val2 = read_operand(memory, reg_file, src, wide);
+            let res = reg_file.alu_sub(val1, val2, wide);
+            write_operand(memory, reg_file, dst, wide, res);
+        }
+
+        OperationType::Adc => {
+            let val1 = read_operand(memory, reg_file, dst, wide);
+            let val2 = read_operand(memory, reg_file, src, wide);
+            let res = reg_file.alu_adc(val1, val2, wide);
+            write_operand(memory, reg_file, dst, wide, res);
+        }
+
+        OperationType::Sbb => {
+            let val1 = read_operand(memory, reg_file, dst, wide);
+            let val2 = read_operand(memory, reg_file, src, wide);
+            let res = reg_file.alu_sbb(val1, val2, wide);
+            write_operand(memory, reg_file, dst, wide, res);
+        }
+
+        OperationType::Inc | OperationType::Dec | OperationType::Neg => {
+            let operand = unary_operand(instruction);
+            let val = read_operand(memory, reg_file, operand, wide);
+            let res = match instruction.op {
+                OperationType::Inc => reg_file.alu_inc(val, wide),
+                OperationType::Dec => reg_file.alu_dec(val, wide),
+                _ => reg_file.alu_neg(val, wide),
+            };
+            write_operand(memory, reg_file, operand, wide, res);
+        }
+
+        OperationType::And | OperationType::Or | OperationType::Xor | OperationType::Test => {
+            let val1 = read_operand(memory, reg_file, dst, wide);
+            let val2 = read_operand(memory, reg_file, src, wide);
+            let res = match instruction.op {
+                OperationType::Or => reg_file.alu_or(val1, val2, wide),
+                OperationType::Xor => reg_file.alu_xor(val1, val2, wide),
+                _ => reg_file.alu_and(val1, val2, wide),
+            };
+            // TEST is just AND without writing result
+            if instruction.op != OperationType::Test {
+                write_operand(memory, reg_file, dst, wide, res);
+            }
+        }
+
+        OperationType::Not => {
+            // NOT is the only logical operation that leaves the flags alone
+            let val = read_operand(memory, reg_file, dst, wide);
+            write_operand(memory, reg_file, dst, wide, !val);
+        }
+
+        OperationType::Shl | OperationType::Sal | OperationType::Shr | OperationType::Sar |
+        OperationType::Rol | OperationType::Ror | OperationType::Rcl | OperationType::Rcr => {
+            let val = read_operand(memory, reg_file, dst, wide);
+            let count = read_operand(memory, reg_file, src, false) as u8;
+            let res = reg_file.alu_shift(instruction.op, val, count, wide);
+            write_operand(memory, reg_file, dst, wide, res);
+        }
+
+        OperationType::Mul | OperationType::Imul => {
+            let val = read_operand(memory, reg_file, unary_operand(instruction), wide);
+            if instruction.op == OperationType::Mul {
+                reg_file.alu_mul(val, wide);
+            } else {
+                reg_file.alu_imul(val, wide);
+            }
+        }
+
+        OperationType::Div | OperationType::Idiv => {
+            let val = read_operand(memory, reg_file, unary_operand(instruction), wide);
+            let ok = if instruction.op == OperationType::Div {
+                reg_file.alu_div(val, wide)
+            } else {
+                reg_file.alu_idiv(val, wide)
+            };
+            // The 8086 pushes the address of the next instruction for a divide error
+            if !ok {
+                raised = interrupt(memory, reg_file, DIVIDE_ERROR);
+            }
+        }
+
+        OperationType::Movs | OperationType::Cmps | OperationType::Scas | OperationType::Lods | OperationType::Stos => {
+            let source_segment = instruction.prefixes.segment.unwrap_or(RegisterIndex::DS);
+
+            if let Some(rep) = instruction.prefixes.rep {
+                // REPE stops CMPS/SCAS on a mismatch, REPNE on a match
+                let repne = rep == RepPrefix::Repne;
+                let compares = matches!(instruction.op, OperationType::Cmps | OperationType::Scas);
+
+                while reg_file.cx != 0 {
+                    string_operation(instruction.op, memory, reg_file, source_segment, wide);
+                    reg_file.cx = reg_file.cx.wrapping_sub(1);
+                    if compares && reg_file.get_flag(Flag::Zero) == repne {
+                        break;
+                    }
+                }
+            } else {
+                string_operation(instruction.op, memory, reg_file, source_segment, wide);
+            }
+        }
+
+        OperationType::Aaa => reg_file.alu_aaa(),
+        OperationType::Aas => reg_file.alu_aas(),
+        OperationType::Daa => reg_file.alu_daa(),
+        OperationType::Das => reg_file.alu_das(),
+
+        OperationType::Aam => {
+            let base = read_operand(memory, reg_file, dst, false) as u8;
+            if !reg_file.alu_aam(base) {
+                raised = interrupt(memory, reg_file, DIVIDE_ERROR);
+            }
+        }
+
+        OperationType::Aad => {
+            let base = read_operand(memory, reg_file, dst, false) as u8;
+            reg_file.alu_aad(base);
+        }
+
+        OperationType::In => {
+            // The port is either an immediate byte or DX
+            let port = read_operand(memory, reg_file, src, true);
+            let val = ports.read(port, wide);
+            write_operand(memory, reg_file, dst, wide, val);
+        }
+
+        OperationType::Out => {
+            let port = read_operand(memory, reg_file, dst, true);
+            let val = read_operand(memory, reg_file, src, wide);
+            ports.write(port, wide, val);
+        }
+
+        OperationType::Xchg => {
+            let val1 = read_operand(memory, reg_file, dst, wide);
+            let val2 = read_operand(memory, reg_file, src, wide);
+            write_operand(memory, reg_file, dst, wide, val2);
+            write_operand(memory, reg_file, src, wide, val1);
+        }
+
+        OperationType::Xlat => {
+            let segment = instruction.prefixes.segment.unwrap_or(RegisterIndex::DS);
+            let at = SegmentedAccess {
+                segment_base: reg_file.get_register_value(&word_register(segment)),
+                segment_offset: reg_file.bx.wrapping_add(reg_file.ax & 0xFF),
+            };
+            let val = memory.read_value(&at, false);
+            reg_file.ax = (reg_file.ax & 0xFF00) | val;
+        }
+
+        OperationType::Lea => {
+            // LEA only forms the offset, it never touches memory
+            if let Operand::Memory(address) = src {
+                let at = effective_address(reg_file, address);
+                write_operand(memory, reg_file, dst, true, at.segment_offset);
+            }
+        }
+
+        OperationType::Lds | OperationType::Les => {
+            if let Some((offset, segment)) = read_far_pointer(memory, reg_file, src) {
+                write_operand(memory, reg_file, dst, true, offset);
+                if instruction.op == OperationType::Lds {
+                    reg_file.ds = segment;
+                } else {
+                    reg_file.es = segment;
+                }
             }
         }
 
+        OperationType::Lahf => {
+            reg_file.ax = (reg_file.ax & 0x00FF) | (reg_file.flags << 8);
+        }
+
+        OperationType::Sahf => {
+            reg_file.load_flags(reg_file.ax >> 8, FLAGS_SAHF_WRITABLE);
+        }
+
+        OperationType::Cbw => {
+            reg_file.ax = reg_file.ax as u8 as i8 as i16 as u16;
+        }
+
+        OperationType::Cwd => {
+            reg_file.dx = if (reg_file.ax & 0x8000) != 0 { 0xFFFF } else { 0 };
+        }
+
+        OperationType::Int => {
+            let vector = read_operand(memory, reg_file, dst, false) as u8;
+            raised = interrupt(memory, reg_file, vector);
+        }
+
+        OperationType::Int3 => raised = interrupt(memory, reg_file, BREAKPOINT),
+
+        OperationType::Into if reg_file.get_flag(Flag::Overflow) => {
+            raised = interrupt(memory, reg_file, OVERFLOW);
+        }
+
+        OperationType::Iret => {
+            reg_file.ip = pop_word(memory, reg_file);
+            reg_file.cs = pop_word(memory, reg_file);
+            let value = pop_word(memory, reg_file);
+            reg_file.load_flags(value, FLAGS_WRITABLE);
+        }
+
+        OperationType::Clc => reg_file.set_flag(Flag::Carry, false),
+        OperationType::Stc => reg_file.set_flag(Flag::Carry, true),
+        OperationType::Cmc => {
+            let carry = reg_file.get_flag(Flag::Carry);
+            reg_file.set_flag(Flag::Carry, !carry);
+        }
+        OperationType::Cld => reg_file.set_flag(Flag::Direction, false),
+        OperationType::Std => reg_file.set_flag(Flag::Direction, true),
+        OperationType::Cli => reg_file.set_flag(Flag::Interrupt, false),
+        OperationType::Sti => reg_file.set_flag(Flag::Interrupt, true),
+
         OperationType::Cmp => {
-            if let Operand::Register(dst) = &instruction.operands[0] {
-                let val1 = reg_file.get_register_value(dst);
-                let val2 = match &instruction.operands[1] {
-                    Operand::Immediate(v) => (*v & 0xFFFF) as u16,
-                    Operand::Register(src) => reg_file.get_register_value(src),
-                    Operand::Memory(_) => 0,
-                    _ => 0,
+            let val1 = read_operand(memory, reg_file, dst, wide);
+            let val2 = read_operand(memory, reg_file, src, wide);
+            // Compare is just SUB without writing result
+            let _ = reg_file.alu_sub(val1, val2, wide);
+        }
+
+        OperationType::Je | OperationType::Jne | OperationType::Jl | OperationType::Jnl |
+        OperationType::Jle | OperationType::Jg | OperationType::Jb | OperationType::Jnb |
+        OperationType::Jbe | OperationType::Ja | OperationType::Jp | OperationType::Jnp |
+        OperationType::Jo | OperationType::Jno | OperationType::Js | OperationType::Jns => {
+            if let Operand::RelativeImmediate(offset) = dst
+                && jump_condition(instruction.op, reg_file)
+            {
+                jump_relative(instruction, reg_file, *offset);
+            }
+        }
+
+        OperationType::Loop | OperationType::Loopz | OperationType::Loopnz => {
+            if let Operand::RelativeImmediate(offset) = dst {
+                reg_file.cx = reg_file.cx.wrapping_sub(1);
+                let zf = reg_file.get_flag(Flag::Zero);
+                let taken = reg_file.cx != 0 && match instruction.op {
+                    OperationType::Loopz => zf,
+                    OperationType::Loopnz => !zf,
+                    _ => true,
                 };
-                // Compare is just SUB without writing result
-                let _ = reg_file.alu_sub(val1, val2);
+                if taken {
+                    jump_relative(instruction, reg_file, *offset);
+                }
             }
         }
 
+        OperationType::Jcxz => {
+            if let Operand::RelativeImmediate(offset) = dst
+                && reg_file.cx == 0
+            {
+                jump_relative(instruction, reg_file, *offset);
+            }
+        }
+
+        OperationType::Jmp => {
+            match dst {
+                Operand::RelativeImmediate(offset) => jump_relative(instruction, reg_file, *offset),
+                _ if far => {
+                    if let Some((offset, segment)) = read_far_pointer(memory, reg_file, dst) {
+                        reg_file.cs = segment;
+                        reg_file.ip = offset;
+                    }
+                }
+                Operand::Register(_) | Operand::Memory(_) => {
+                    reg_file.ip = read_operand(memory, reg_file, dst, true);
+                }
+                _ => {}
+            }
+        }
+
+        OperationType::Push => {
+            let operand = unary_operand(instruction);
+            // PUSH SP stores the already decremented value on the 8086
+            let value = match operand {
+                Operand::Register(reg) if reg.index == RegisterIndex::SP => reg_file.sp.wrapping_sub(2),
+                _ => read_operand(memory, reg_file, operand, true),
+            };
+            push_word(memory, reg_file, value);
+        }
+
+        OperationType::Pop => {
+            let value = pop_word(memory, reg_file);
+            write_operand(memory, reg_file, unary_operand(instruction), true, value);
+        }
+
+        OperationType::Pushf => {
+            push_word(memory, reg_file, reg_file.flags);
+        }
+
+        OperationType::Popf => {
+            let value = pop_word(memory, reg_file);
+            reg_file.load_flags(value, FLAGS_WRITABLE);
+        }
+
+        OperationType::Call => {
+            match dst {
+                Operand::RelativeImmediate(offset) => {
+                    push_word(memory, reg_file, reg_file.ip);
+                    jump_relative(instruction, reg_file, *offset);
+                }
+                _ if far => {
+                    if let Some((offset, segment)) = read_far_pointer(memory, reg_file, dst) {
+                        push_word(memory, reg_file, reg_file.cs);
+                        push_word(memory, reg_file, reg_file.ip);
+                        reg_file.cs = segment;
+                        reg_file.ip = offset;
+                    }
+                }
+                Operand::Register(_) | Operand::Memory(_) => {
+                    let target = read_operand(memory, reg_file, dst, true);
+                    push_word(memory, reg_file, reg_file.ip);
+                    reg_file.ip = target;
+                }
+                _ => {}
+            }
+        }
+
+        OperationType::Ret | OperationType::Retf => {
+            reg_file.ip = pop_word(memory, reg_file);
+            if instruction.op == OperationType::Retf {
+                reg_file.cs = pop_word(memory, reg_file);
+            }
+            // The optional immediate releases caller-pushed arguments
+            if let Operand::Immediate(bytes) = dst {
+                reg_file.sp = reg_file.sp.wrapping_add(*bytes as u16);
+            }
+        }
+
+        // Without a coprocessor ESC only performs the operand's bus cycle
+        OperationType::Esc => {
+            if let Some(fpu) = fpu {
+                fpu.execute(instruction, memory, reg_file);
+            }
+        }
+
+        // The emulated 8087 completes each instruction at once, so WAIT never
+        // stalls; it is where a pending unmasked exception gets delivered
+        OperationType::Wait if fpu.as_ref().is_some_and(|fpu| fpu.interrupt_pending()) => {
+            raised = interrupt(memory, reg_file, NMI);
+        }
+
+        // HLT is handled by the simulator, which owns the halted state
+        OperationType::Hlt => {}
+
         _ => {}
     }
-    Ok(())
+
+    if trap && raised.is_ok() {
+        raised = interrupt(memory, reg_file, SINGLE_STEP);
+    }
+
+    raised
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::register::Flag;
+    use crate::simulator::Simulator;
+
+    /// Steps `count` instructions of `simulator`.
+    fn step(simulator: &mut Simulator, count: usize) {
+        for _ in 0..count {
+            simulator.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn cmp_and_inc_only_touch_the_flags_they_should() {
+        // stc; mov al, 0FFh; inc al; cmp al, 1
+        let mut simulator = Simulator::with_code(&[0xF9, 0xB0, 0xFF, 0xFE, 0xC0, 0x3C, 0x01]);
+
+        step(&mut simulator, 3);
+        assert_eq!(simulator.registers.ax & 0xFF, 0x00);
+        assert!(simulator.registers.get_flag(Flag::Carry), "INC cleared CF");
+        assert!(simulator.registers.get_flag(Flag::Zero));
+
+        simulator.registers.set_flag(Flag::Carry, false);
+        step(&mut simulator, 1);
+        assert_eq!(simulator.registers.ax & 0xFF, 0x00, "CMP wrote its result");
+        assert!(simulator.registers.get_flag(Flag::Carry));
+        assert!(simulator.registers.get_flag(Flag::Sign));
+    }
+
+    #[test]
+    fn shift_counts_come_from_cl() {
+        // shl al, cl; shl al, cl
+        let mut simulator = Simulator::with_code(&[0xD2, 0xE0, 0xD2, 0xE0]);
+        simulator.registers.ax = 0x0081;
+        simulator.registers.set_flag(Flag::Carry, true);
+        simulator.registers.set_flag(Flag::Zero, true);
+
+        step(&mut simulator, 1);
+        assert_eq!(simulator.registers.ax, 0x0081);
+        assert!(simulator.registers.get_flag(Flag::Carry) && simulator.registers.get_flag(Flag::Zero));
+
+        simulator.registers.cx = 2;
+        step(&mut simulator, 1);
+        assert_eq!(simulator.registers.ax, 0x0004);
+        assert!(!simulator.registers.get_flag(Flag::Carry));
+        assert!(!simulator.registers.get_flag(Flag::Zero));
+    }
+
+    #[test]
+    fn divide_errors_push_the_address_after_the_instruction() {
+        // (code, AX, DX, divisor in BX or CX)
+        let cases: [(&[u8], u16, u16, u16); 3] = [
+            // idiv bl with AX = -128 and BL = 1
+            (&[0xF6, 0xFB], 0xFF80, 0, 1),
+            // idiv cx with DX:AX = -32768 and CX = -1
+            (&[0xF7, 0xF9], 0x8000, 0xFFFF, 0xFFFF),
+            // div cx with CX = 0
+            (&[0xF7, 0xF1], 0x1234, 0, 0),
+        ];
+        for (code, ax, dx, divisor) in cases {
+            let mut simulator = Simulator::with_code(code);
+            simulator.set_vector(0, 0x200, 0x0010);
+            simulator.registers.ax = ax;
+            simulator.registers.dx = dx;
+            simulator.registers.bx = divisor;
+            simulator.registers.cx = divisor;
+            simulator.registers.set_flag(Flag::Interrupt, true);
+            let flags = simulator.registers.flags;
+
+            step(&mut simulator, 1);
+
+            assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x200, 0x0010), "{code:02X?}");
+            assert_eq!((simulator.registers.ax, simulator.registers.dx), (ax, dx));
+            assert_eq!(simulator.registers.sp, 0x1000 - 6);
+            let frame = [0xFFA, 0xFFC, 0xFFE].map(|address| simulator.read_word(address));
+            assert_eq!(frame, [code.len() as u16, Simulator::TEST_CODE_SEGMENT, flags]);
+            assert!(!simulator.registers.get_flag(Flag::Interrupt));
+        }
+    }
+
+    /// A simulator running `code` with DS:0300 and ES:0000 at physical
+    /// 0x300 and 0x500.
+    fn strings(code: &[u8], source: &[u8], destination: &[u8], cx: u16) -> Simulator {
+        let mut simulator = Simulator::with_code(code);
+        simulator.load(0x300, source);
+        simulator.load(0x500, destination);
+        simulator.registers.si = 0x300;
+        simulator.registers.es = 0x50;
+        simulator.registers.cx = cx;
+        simulator
+    }
+
+    #[test]
+    fn repe_and_repne_stop_on_the_comparison() {
+        // repe cmpsb stops at the first mismatch
+        let mut simulator = strings(&[0xF3, 0xA6], b"ABCX", b"ABCD", 10);
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cx, simulator.registers.si, simulator.registers.di), (6, 0x304, 4));
+        assert!(!simulator.registers.get_flag(Flag::Zero));
+
+        // and runs CX out while everything matches
+        let mut simulator = strings(&[0xF3, 0xA6], b"ABCX", b"ABCD", 3);
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cx, simulator.registers.di), (0, 3));
+        assert!(simulator.registers.get_flag(Flag::Zero));
+
+        // repne scasb stops at the first match
+        let mut simulator = strings(&[0xF2, 0xAE], b"", b"ABCD", 10);
+        simulator.registers.ax = b'C' as u16;
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cx, simulator.registers.di), (7, 3));
+        assert!(simulator.registers.get_flag(Flag::Zero));
+    }
+
+    #[test]
+    fn direction_flag_steps_backward() {
+        // std; rep movsb from the last byte of each buffer
+        let mut simulator = strings(&[0xFD, 0xF3, 0xA4], b"xyz", b"...", 3);
+        simulator.registers.si = 0x302;
+        simulator.registers.di = 2;
+        step(&mut simulator, 2);
+        assert_eq!(&simulator.memory.bytes[0x500..0x503], b"xyz");
+        assert_eq!((simulator.registers.si, simulator.registers.di), (0x2FF, 0xFFFF));
+
+        // std; lodsw steps by two
+        let mut simulator = strings(&[0xFD, 0xAD], &[0x34, 0x12], b"", 0);
+        step(&mut simulator, 2);
+        assert_eq!((simulator.registers.ax, simulator.registers.si), (0x1234, 0x2FE));
+    }
+
+    #[test]
+    fn rep_with_cx_zero_does_nothing() {
+        // rep stosb
+        let mut simulator = strings(&[0xF3, 0xAA], b"", b"....", 0);
+        simulator.registers.ax = b'!' as u16;
+        step(&mut simulator, 1);
+        assert_eq!(&simulator.memory.bytes[0x500..0x504], b"....");
+        assert_eq!((simulator.registers.cx, simulator.registers.di), (0, 0));
+        assert_eq!(simulator.registers.ip, 2);
+    }
+
+    #[test]
+    fn segment_overrides_move_the_source_only() {
+        // cs: movsb copies from CS:SI to ES:DI
+        let mut simulator = strings(&[0x2E, 0xA4], b"d", b".", 0);
+        simulator.registers.si = 0x80;
+        simulator.load(0x1080, b"c");
+        step(&mut simulator, 1);
+        assert_eq!(simulator.memory.bytes[0x500], b'c');
+        assert_eq!(simulator.memory.bytes[0x1000], 0x2E, "wrote through CS");
+
+        // ss: stosb still stores to ES:DI
+        let mut simulator = strings(&[0x36, 0xAA], b"", b".", 0);
+        simulator.registers.ss = 0x300;
+        simulator.registers.ax = b'!' as u16;
+        step(&mut simulator, 1);
+        assert_eq!(simulator.memory.bytes[0x500], b'!');
+        assert_eq!(simulator.memory.bytes[0x3000], 0);
+    }
+
+    #[test]
+    fn int_pushes_a_frame_that_iret_unwinds() {
+        // int 21h, with TF and IF set; both handlers are just iret
+        let mut simulator = Simulator::with_code(&[0xCD, 0x21]);
+        simulator.set_vector(0x21, 0x200, 0x0010);
+        simulator.set_vector(1, 0x300, 0x0020);
+        simulator.load(0x2010, &[0xCF]);
+        simulator.load(0x3020, &[0xCF]);
+        simulator.registers.set_flag(Flag::Trap, true);
+        simulator.registers.set_flag(Flag::Interrupt, true);
+        simulator.registers.set_flag(Flag::Carry, true);
+        let flags = simulator.registers.flags;
+        let cleared = flags & !(1 << Flag::Trap as u16) & !(1 << Flag::Interrupt as u16);
+
+        // The single step still traps after the INT, at the first handler
+        // instruction, and each frame clears TF and IF
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x300, 0x0020));
+        assert_eq!(simulator.registers.flags, cleared);
+        let frames = (0..6).map(|i| simulator.read_word(0x1000 - 12 + i * 2)).collect::<Vec<_>>();
+        assert_eq!(frames, [0x0010, 0x0200, cleared, 0x0002, Simulator::TEST_CODE_SEGMENT, flags]);
+
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x200, 0x0010));
+        assert_eq!(simulator.registers.flags, cleared);
+
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (Simulator::TEST_CODE_SEGMENT, 2));
+        assert_eq!((simulator.registers.flags, simulator.registers.sp), (flags, 0x1000));
+    }
+
+    #[test]
+    fn into_only_fires_on_overflow() {
+        // into; into
+        let mut simulator = Simulator::with_code(&[0xCE, 0xCE]);
+        simulator.set_vector(4, 0x200, 0);
+
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (Simulator::TEST_CODE_SEGMENT, 1));
+
+        simulator.registers.set_flag(Flag::Overflow, true);
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x200, 0));
+        assert_eq!(simulator.read_word(0x1000 - 6), 2);
+    }
+
+    #[test]
+    fn far_jumps_and_calls_load_cs_and_ip() {
+        // jmp 0200:0010
+        let mut simulator = Simulator::with_code(&[0xEA, 0x10, 0x00, 0x00, 0x02]);
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x200, 0x0010));
+        assert_eq!(simulator.registers.sp, 0x1000);
+
+        // call 0200:0010, where a retf returns
+        let mut simulator = Simulator::with_code(&[0x9A, 0x10, 0x00, 0x00, 0x02]);
+        simulator.load(0x2010, &[0xCB]);
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x200, 0x0010));
+        assert_eq!([simulator.read_word(0xFFC), simulator.read_word(0xFFE)], [5, Simulator::TEST_CODE_SEGMENT]);
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (Simulator::TEST_CODE_SEGMENT, 5));
+        assert_eq!(simulator.registers.sp, 0x1000);
+    }
+
+    #[test]
+    fn indirect_far_transfers_read_offset_then_segment() {
+        // jmp far [0300h]
+        let mut simulator = Simulator::with_code(&[0xFF, 0x2E, 0x00, 0x03]);
+        simulator.load(0x300, &[0x20, 0x00, 0x00, 0x03]);
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x300, 0x0020));
+
+        // call far [bx]
+        let mut simulator = Simulator::with_code(&[0xFF, 0x1F]);
+        simulator.load(0x300, &[0x20, 0x00, 0x00, 0x03]);
+        simulator.registers.bx = 0x300;
+        step(&mut simulator, 1);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x300, 0x0020));
+        assert_eq!([simulator.read_word(0xFFC), simulator.read_word(0xFFE)], [2, Simulator::TEST_CODE_SEGMENT]);
+    }
 }
@@ -0,0 +1,1658 @@
+use std::cmp::Ordering;
+
+use crate::{
+    decoder::{Instruction, Operand},
+    execution_unit::effective_address,
+    instruction_formats::OperationType,
+    memory::{Memory, SegmentedAccess},
+    register::{EffectiveAddressExpression, RegisterFile, RegisterIndex},
+};
+
+const EXPONENT_BIAS: i32 = 16383;
+const MAX_EXPONENT: u16 = 0x7FFF;
+const INTEGER_BIT: u64 = 1 << 63;
+
+/// An 80-bit temporary real as the 8087 holds it: a 64-bit significand with
+/// an explicit integer bit, a 15-bit biased exponent and a sign.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct F80 {
+    pub significand: u64,
+    pub sign_exponent: u16,
+}
+
+/// Scales `value` by 2^`exponent` without overflowing the intermediate power.
+fn scale(mut value: f64, mut exponent: i32) -> f64 {
+    while exponent > 1000 {
+        value *= 2f64.powi(1000);
+        exponent -= 1000;
+    }
+    while exponent < -1000 {
+        value *= 2f64.powi(-1000);
+        exponent += 1000;
+    }
+    value * 2f64.powi(exponent)
+}
+
+impl F80 {
+    pub const ZERO: F80 = F80 { significand: 0, sign_exponent: 0 };
+    pub const ONE: F80 = F80 { significand: INTEGER_BIT, sign_exponent: 0x3FFF };
+    /// The NaN masked invalid operations produce.
+    pub const INDEFINITE: F80 = F80 { significand: 0xC000_0000_0000_0000, sign_exponent: 0xFFFF };
+
+    fn exponent(self) -> u16 {
+        self.sign_exponent & MAX_EXPONENT
+    }
+
+    pub fn is_negative(self) -> bool {
+        (self.sign_exponent & 0x8000) != 0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.exponent() == 0 && self.significand == 0
+    }
+
+    pub fn is_nan(self) -> bool {
+        self.exponent() == MAX_EXPONENT && (self.significand << 1) != 0
+    }
+
+    pub fn is_infinite(self) -> bool {
+        self.exponent() == MAX_EXPONENT && self.significand == INTEGER_BIT
+    }
+
+    /// Pseudo-zeros and the like: a zero significand under a nonzero
+    /// exponent. The 8087 refuses these as invalid operands.
+    fn is_unsupported(self) -> bool {
+        self.exponent() != 0 && self.significand == 0
+    }
+
+    fn is_denormal(self) -> bool {
+        self.exponent() == 0 && self.significand != 0
+    }
+
+    fn is_finite_nonzero(self) -> bool {
+        !self.is_zero() && self.exponent() != MAX_EXPONENT && !self.is_unsupported()
+    }
+
+    fn negate(self) -> Self {
+        Self { sign_exponent: self.sign_exponent ^ 0x8000, ..self }
+    }
+
+    fn with_sign(self, negative: bool) -> Self {
+        Self { sign_exponent: (self.sign_exponent & MAX_EXPONENT) | if negative { 0x8000 } else { 0 }, ..self }
+    }
+
+    pub fn from_bytes(bytes: [u8; 10]) -> Self {
+        let mut significand = [0u8; 8];
+        significand.copy_from_slice(&bytes[..8]);
+        Self {
+            significand: u64::from_le_bytes(significand),
+            sign_exponent: u16::from_le_bytes([bytes[8], bytes[9]]),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 10] {
+        let mut bytes = [0u8; 10];
+        bytes[..8].copy_from_slice(&self.significand.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.sign_exponent.to_le_bytes());
+        bytes
+    }
+
+    /// Exact conversion; every double is representable.
+    pub fn from_f64(value: f64) -> Self {
+        let bits = value.to_bits();
+        let sign = ((bits >> 63) as u16) << 15;
+        let exponent = ((bits >> 52) & 0x7FF) as i32;
+        let fraction = bits & ((1 << 52) - 1);
+
+        let (significand, exponent) = match exponent {
+            0 if fraction == 0 => (0, 0),
+            0 => {
+                // Subnormal doubles are normal in the wider format
+                let shift = fraction.leading_zeros();
+                (fraction << shift, (EXPONENT_BIAS - 1011 - shift as i32) as u16)
+            }
+            0x7FF => (INTEGER_BIT | (fraction << 11), MAX_EXPONENT),
+            _ => (INTEGER_BIT | (fraction << 11), (exponent - 1023 + EXPONENT_BIAS) as u16),
+        };
+
+        Self { significand, sign_exponent: sign | exponent }
+    }
+
+    /// Rounds to the nearest double, overflowing to infinity.
+    pub fn to_f64(self) -> f64 {
+        let magnitude = if self.exponent() == MAX_EXPONENT {
+            if self.is_nan() { f64::NAN } else { f64::INFINITY }
+        } else {
+            scale(self.significand as f64, self.exponent().max(1) as i32 - EXPONENT_BIAS - 63)
+        };
+        if self.is_negative() { -magnitude } else { magnitude }
+    }
+
+    pub fn from_i64(value: i64) -> Self {
+        Self::from_magnitude(value < 0, value.unsigned_abs())
+    }
+
+    fn from_magnitude(negative: bool, magnitude: u64) -> Self {
+        if magnitude == 0 {
+            return Self::ZERO.with_sign(negative);
+        }
+        let shift = magnitude.leading_zeros();
+        Self {
+            significand: magnitude << shift,
+            sign_exponent: (EXPONENT_BIAS as u16 + 63 - shift as u16),
+        }
+        .with_sign(negative)
+    }
+
+    /// Splits a finite nonzero value into a signed mantissa and a power of
+    /// two, or `None` for anything else.
+    fn split(self) -> Option<(f64, i32)> {
+        if !self.is_finite_nonzero() {
+            return None;
+        }
+        let mantissa = scale(self.significand as f64, -63);
+        let exponent = self.exponent().max(1) as i32 - EXPONENT_BIAS;
+        Some((if self.is_negative() { -mantissa } else { mantissa }, exponent))
+    }
+
+    /// Sign, power and significand of a finite nonzero value, normalized so
+    /// the value is significand * 2^(power - 63) with the integer bit set.
+    fn unpack(self) -> (bool, i32, u64) {
+        let shift = self.significand.leading_zeros();
+        let power = self.exponent().max(1) as i32 - EXPONENT_BIAS - shift as i32;
+        (self.is_negative(), power, self.significand << shift)
+    }
+
+    /// Rounds `magnitude` * 2^`power` to `bits` significant bits under
+    /// `rounding`, returning the result and the exceptions it raises.
+    fn round(negative: bool, magnitude: u128, power: i32, bits: u32, rounding: Rounding) -> (Self, u16) {
+        let (exponent, kept, exceptions) = Self::round_real(negative, magnitude, power, bits, EXPONENT_BIAS, rounding);
+        let significand = kept << (64 - bits);
+        (Self { significand, sign_exponent: exponent as u16 }.with_sign(negative), exceptions)
+    }
+
+    /// Rounds `magnitude` * 2^`power` into a real format with `bits`
+    /// significant bits and an exponent biased by `bias`. Returns the biased
+    /// exponent, the significand with its integer bit and the exceptions
+    /// raised; denormals come back with exponent zero and infinity with the
+    /// all-ones exponent.
+    fn round_real(
+        negative: bool, magnitude: u128, power: i32, bits: u32, bias: i32, rounding: Rounding,
+    ) -> (i32, u64, u16) {
+        if magnitude == 0 {
+            return (0, 0, 0);
+        }
+
+        // Put the leading bit at bit 127; denormals keep fewer bits
+        let shift = magnitude.leading_zeros();
+        let magnitude = magnitude << shift;
+        let exponent = power - shift as i32 + 127 + bias;
+        let tiny = exponent <= 0;
+        let drop = 128 - bits as i32 + if tiny { 1 - exponent } else { 0 };
+
+        let (kept, round_bit, sticky) = match drop {
+            129.. => (0, false, true),
+            128 => (0, true, (magnitude << 1) != 0),
+            _ => (
+                magnitude >> drop,
+                ((magnitude >> (drop - 1)) & 1) != 0,
+                (magnitude & ((1 << (drop - 1)) - 1)) != 0,
+            ),
+        };
+        let inexact = round_bit || sticky;
+        let round_up = match rounding {
+            Rounding::Nearest => round_bit && (sticky || (kept & 1) != 0),
+            Rounding::Down => negative && inexact,
+            Rounding::Up => !negative && inexact,
+            Rounding::Chop => false,
+        };
+        let mut kept = kept + round_up as u128;
+
+        let mut exceptions = if inexact { STATUS_PRECISION } else { 0 };
+        let exponent = if tiny {
+            if inexact {
+                exceptions |= STATUS_UNDERFLOW;
+            }
+            // Rounding up may carry into the smallest normal
+            (kept >> (bits - 1)) as i32
+        } else if kept >> bits != 0 {
+            kept >>= 1;
+            exponent + 1
+        } else {
+            exponent
+        };
+
+        let max_exponent = 2 * bias + 1;
+        if exponent >= max_exponent {
+            let to_infinity = match rounding {
+                Rounding::Nearest => true,
+                Rounding::Down => negative,
+                Rounding::Up => !negative,
+                Rounding::Chop => false,
+            };
+            let (exponent, kept) =
+                if to_infinity { (max_exponent, 1 << (bits - 1)) } else { (max_exponent - 1, u64::MAX >> (64 - bits)) };
+            return (exponent, kept, STATUS_OVERFLOW | STATUS_PRECISION);
+        }
+
+        (exponent, kept as u64, exceptions)
+    }
+
+    /// Encodes as an IEEE real with `bits` significant bits and an
+    /// `exponent_bits` wide exponent, rounding under `rounding`. NaNs come
+    /// out quiet with the top of their payload.
+    fn to_real(self, bits: u32, exponent_bits: u32, rounding: Rounding) -> (u64, u16) {
+        let max_exponent = (1u64 << exponent_bits) - 1;
+        let fraction_mask = (1u64 << (bits - 1)) - 1;
+        let (exponent, fraction, exceptions) = if self.is_nan() {
+            let payload = (self.significand << 1) >> (65 - bits);
+            (max_exponent, payload | (1 << (bits - 2)), 0)
+        } else if self.is_infinite() {
+            (max_exponent, 0, 0)
+        } else if self.is_zero() {
+            (0, 0, 0)
+        } else {
+            let (negative, power, significand) = self.unpack();
+            let bias = (max_exponent >> 1) as i32;
+            let (exponent, kept, exceptions) =
+                Self::round_real(negative, significand as u128, power - 63, bits, bias, rounding);
+            (exponent as u64, kept & fraction_mask, exceptions)
+        };
+        let sign = (self.is_negative() as u64) << (bits - 1 + exponent_bits);
+        (sign | (exponent << (bits - 1)) | fraction, exceptions)
+    }
+
+    /// Rounds to an integer under `rounding`, or `None` for NaNs, infinities,
+    /// unsupported encodings and values too large for any 8087 integer format.
+    fn round_to_integer(self, rounding: Rounding) -> Option<i128> {
+        if self.exponent() == MAX_EXPONENT || self.is_unsupported() {
+            return None;
+        }
+        if self.significand == 0 {
+            return Some(0);
+        }
+
+        let negative = self.is_negative();
+        let shift = 63 - (self.exponent().max(1) as i32 - EXPONENT_BIAS);
+        if shift < -63 {
+            return None;
+        }
+
+        let significand = self.significand as u128;
+        let (integer, remainder, half) = if shift <= 0 {
+            (significand << -shift, 0, 1)
+        } else if shift >= 128 {
+            (0, 1, 2)
+        } else {
+            (significand >> shift, significand & ((1 << shift) - 1), 1u128 << (shift - 1))
+        };
+
+        let round_up = match rounding {
+            Rounding::Nearest => remainder > half || (remainder == half && (integer & 1) != 0),
+            Rounding::Down => negative && remainder != 0,
+            Rounding::Up => !negative && remainder != 0,
+            Rounding::Chop => false,
+        };
+
+        let magnitude = (integer + round_up as u128) as i128;
+        Some(if negative { -magnitude } else { magnitude })
+    }
+
+    fn compare(self, other: F80) -> Option<Ordering> {
+        if self.is_nan() || other.is_nan() || self.is_unsupported() || other.is_unsupported() {
+            return None;
+        }
+        if self.is_zero() && other.is_zero() {
+            return Some(Ordering::Equal);
+        }
+
+        let magnitude = |v: F80| (v.exponent(), v.significand);
+        Some(match (self.is_negative(), other.is_negative()) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => magnitude(self).cmp(&magnitude(other)),
+            (true, true) => magnitude(other).cmp(&magnitude(self)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rounding {
+    Nearest,
+    Down,
+    Up,
+    Chop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arithmetic {
+    Add,
+    Mul,
+    Sub,
+    Subr,
+    Div,
+    Divr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FpuOperation {
+    Fadd,
+    Fmul,
+    Fcom,
+    Fcomp,
+    Fsub,
+    Fsubr,
+    Fdiv,
+    Fdivr,
+    Faddp,
+    Fmulp,
+    Fcompp,
+    Fsubp,
+    Fsubrp,
+    Fdivp,
+    Fdivrp,
+    Fiadd,
+    Fimul,
+    Ficom,
+    Ficomp,
+    Fisub,
+    Fisubr,
+    Fidiv,
+    Fidivr,
+    Fld,
+    Fst,
+    Fstp,
+    Fild,
+    Fist,
+    Fistp,
+    Fbld,
+    Fbstp,
+    Fxch,
+    Ffree,
+    Fldenv,
+    Fldcw,
+    Fnstenv,
+    Fnstcw,
+    Fnstsw,
+    Frstor,
+    Fnsave,
+    Fnop,
+    Fchs,
+    Fabs,
+    Ftst,
+    Fxam,
+    Fld1,
+    Fldl2t,
+    Fldl2e,
+    Fldpi,
+    Fldlg2,
+    Fldln2,
+    Fldz,
+    F2xm1,
+    Fyl2x,
+    Fptan,
+    Fpatan,
+    Fxtract,
+    Fdecstp,
+    Fincstp,
+    Fprem,
+    Fyl2xp1,
+    Fsqrt,
+    Frndint,
+    Fscale,
+    Fneni,
+    Fndisi,
+    Fnclex,
+    Fninit,
+}
+
+impl FpuOperation {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            FpuOperation::Fadd => "fadd",
+            FpuOperation::Fmul => "fmul",
+            FpuOperation::Fcom => "fcom",
+            FpuOperation::Fcomp => "fcomp",
+            FpuOperation::Fsub => "fsub",
+            FpuOperation::Fsubr => "fsubr",
+            FpuOperation::Fdiv => "fdiv",
+            FpuOperation::Fdivr => "fdivr",
+            FpuOperation::Faddp => "faddp",
+            FpuOperation::Fmulp => "fmulp",
+            FpuOperation::Fcompp => "fcompp",
+            FpuOperation::Fsubp => "fsubp",
+            FpuOperation::Fsubrp => "fsubrp",
+            FpuOperation::Fdivp => "fdivp",
+            FpuOperation::Fdivrp => "fdivrp",
+            FpuOperation::Fiadd => "fiadd",
+            FpuOperation::Fimul => "fimul",
+            FpuOperation::Ficom => "ficom",
+            FpuOperation::Ficomp => "ficomp",
+            FpuOperation::Fisub => "fisub",
+            FpuOperation::Fisubr => "fisubr",
+            FpuOperation::Fidiv => "fidiv",
+            FpuOperation::Fidivr => "fidivr",
+            FpuOperation::Fld => "fld",
+            FpuOperation::Fst => "fst",
+            FpuOperation::Fstp => "fstp",
+            FpuOperation::Fild => "fild",
+            FpuOperation::Fist => "fist",
+            FpuOperation::Fistp => "fistp",
+            FpuOperation::Fbld => "fbld",
+            FpuOperation::Fbstp => "fbstp",
+            FpuOperation::Fxch => "fxch",
+            FpuOperation::Ffree => "ffree",
+            FpuOperation::Fldenv => "fldenv",
+            FpuOperation::Fldcw => "fldcw",
+            FpuOperation::Fnstenv => "fnstenv",
+            FpuOperation::Fnstcw => "fnstcw",
+            FpuOperation::Fnstsw => "fnstsw",
+            FpuOperation::Frstor => "frstor",
+            FpuOperation::Fnsave => "fnsave",
+            FpuOperation::Fnop => "fnop",
+            FpuOperation::Fchs => "fchs",
+            FpuOperation::Fabs => "fabs",
+            FpuOperation::Ftst => "ftst",
+            FpuOperation::Fxam => "fxam",
+            FpuOperation::Fld1 => "fld1",
+            FpuOperation::Fldl2t => "fldl2t",
+            FpuOperation::Fldl2e => "fldl2e",
+            FpuOperation::Fldpi => "fldpi",
+            FpuOperation::Fldlg2 => "fldlg2",
+            FpuOperation::Fldln2 => "fldln2",
+            FpuOperation::Fldz => "fldz",
+            FpuOperation::F2xm1 => "f2xm1",
+            FpuOperation::Fyl2x => "fyl2x",
+            FpuOperation::Fptan => "fptan",
+            FpuOperation::Fpatan => "fpatan",
+            FpuOperation::Fxtract => "fxtract",
+            FpuOperation::Fdecstp => "fdecstp",
+            FpuOperation::Fincstp => "fincstp",
+            FpuOperation::Fprem => "fprem",
+            FpuOperation::Fyl2xp1 => "fyl2xp1",
+            FpuOperation::Fsqrt => "fsqrt",
+            FpuOperation::Frndint => "frndint",
+            FpuOperation::Fscale => "fscale",
+            FpuOperation::Fneni => "fneni",
+            FpuOperation::Fndisi => "fndisi",
+            FpuOperation::Fnclex => "fnclex",
+            FpuOperation::Fninit => "fninit",
+        }
+    }
+
+    fn arithmetic(&self) -> Option<Arithmetic> {
+        use FpuOperation::*;
+        match self {
+            Fadd | Faddp | Fiadd => Some(Arithmetic::Add),
+            Fmul | Fmulp | Fimul => Some(Arithmetic::Mul),
+            Fsub | Fsubp | Fisub => Some(Arithmetic::Sub),
+            Fsubr | Fsubrp | Fisubr => Some(Arithmetic::Subr),
+            Fdiv | Fdivp | Fidiv => Some(Arithmetic::Div),
+            Fdivr | Fdivrp | Fidivr => Some(Arithmetic::Divr),
+            _ => None,
+        }
+    }
+
+    /// Control instructions leave the saved instruction and operand pointers
+    /// alone so that an exception handler can still find the faulting one.
+    fn is_control(&self) -> bool {
+        use FpuOperation::*;
+        matches!(self, Fldenv | Fldcw | Fnstenv | Fnstcw | Fnstsw | Frstor | Fnsave | Fneni | Fndisi | Fnclex | Fninit)
+    }
+}
+
+/// Layout of an x87 memory operand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryFormat {
+    Real32,
+    Real64,
+    Real80,
+    Int16,
+    Int32,
+    Int64,
+    Bcd,
+    /// Control or status word
+    Word,
+    /// The 14-byte environment of FLDENV and FSTENV
+    Environment,
+    /// The 94-byte environment and register image of FSAVE and FRSTOR
+    State,
+}
+
+impl MemoryFormat {
+    /// NASM size keyword, empty where the operand size is implied.
+    pub fn size_name(&self) -> &'static str {
+        match self {
+            MemoryFormat::Real32 | MemoryFormat::Int32 => "dword",
+            MemoryFormat::Real64 | MemoryFormat::Int64 => "qword",
+            MemoryFormat::Real80 | MemoryFormat::Bcd => "tword",
+            MemoryFormat::Int16 | MemoryFormat::Word => "word",
+            MemoryFormat::Environment | MemoryFormat::State => "",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FpuOperand {
+    #[default]
+    None,
+    /// ST(i), relative to the top of the register stack
+    St(u8),
+    Memory(EffectiveAddressExpression, MemoryFormat),
+}
+
+/// An ESC instruction decoded as the 8087 interprets it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FpuInstruction {
+    pub op: FpuOperation,
+    pub operands: [FpuOperand; 2],
+}
+
+const ARITHMETIC: [FpuOperation; 8] = [
+    FpuOperation::Fadd,
+    FpuOperation::Fmul,
+    FpuOperation::Fcom,
+    FpuOperation::Fcomp,
+    FpuOperation::Fsub,
+    FpuOperation::Fsubr,
+    FpuOperation::Fdiv,
+    FpuOperation::Fdivr,
+];
+
+const INTEGER_ARITHMETIC: [FpuOperation; 8] = [
+    FpuOperation::Fiadd,
+    FpuOperation::Fimul,
+    FpuOperation::Ficom,
+    FpuOperation::Ficomp,
+    FpuOperation::Fisub,
+    FpuOperation::Fisubr,
+    FpuOperation::Fidiv,
+    FpuOperation::Fidivr,
+];
+
+/// R/M number of the word register the decoder put in an ESC register form.
+fn rm_index(index: RegisterIndex) -> u8 {
+    match index {
+        RegisterIndex::A => 0,
+        RegisterIndex::C => 1,
+        RegisterIndex::D => 2,
+        RegisterIndex::B => 3,
+        RegisterIndex::SP => 4,
+        RegisterIndex::BP => 5,
+        RegisterIndex::SI => 6,
+        _ => 7,
+    }
+}
+
+impl FpuInstruction {
+    /// Interprets an ESC instruction. Opcodes the 8087 does not define decode
+    /// to `None` and are left as a plain `esc`.
+    pub fn decode(instruction: &Instruction) -> Option<Self> {
+        if instruction.op != OperationType::Esc {
+            return None;
+        }
+
+        let Operand::Immediate(opcode) = instruction.operands[0] else {
+            return None;
+        };
+        let (group, reg) = ((opcode >> 3) as usize & 7, opcode as usize & 7);
+
+        match instruction.operands[1] {
+            Operand::Memory(address) => Self::decode_memory(group, reg, address),
+            Operand::Register(register) => Self::decode_register(group, reg, rm_index(register.index)),
+            _ => None,
+        }
+    }
+
+    fn decode_memory(group: usize, reg: usize, address: EffectiveAddressExpression) -> Option<Self> {
+        use FpuOperation::*;
+        use MemoryFormat::*;
+
+        let (op, format) = match (group, reg) {
+            (0, _) => (ARITHMETIC[reg], Real32),
+            (2, _) => (INTEGER_ARITHMETIC[reg], Int32),
+            (4, _) => (ARITHMETIC[reg], Real64),
+            (6, _) => (INTEGER_ARITHMETIC[reg], Int16),
+            (1, 0) => (Fld, Real32),
+            (1, 2) => (Fst, Real32),
+            (1, 3) => (Fstp, Real32),
+            (1, 4) => (Fldenv, Environment),
+            (1, 5) => (Fldcw, Word),
+            (1, 6) => (Fnstenv, Environment),
+            (1, 7) => (Fnstcw, Word),
+            (3, 0) => (Fild, Int32),
+            (3, 2) => (Fist, Int32),
+            (3, 3) => (Fistp, Int32),
+            (3, 5) => (Fld, Real80),
+            (3, 7) => (Fstp, Real80),
+            (5, 0) => (Fld, Real64),
+            (5, 2) => (Fst, Real64),
+            (5, 3) => (Fstp, Real64),
+            (5, 4) => (Frstor, State),
+            (5, 6) => (Fnsave, State),
+            (5, 7) => (Fnstsw, Word),
+            (7, 0) => (Fild, Int16),
+            (7, 2) => (Fist, Int16),
+            (7, 3) => (Fistp, Int16),
+            (7, 4) => (Fbld, Bcd),
+            (7, 5) => (Fild, Int64),
+            (7, 6) => (Fbstp, Bcd),
+            (7, 7) => (Fistp, Int64),
+            _ => return None,
+        };
+
+        Some(Self { op, operands: [FpuOperand::Memory(address, format), FpuOperand::None] })
+    }
+
+    fn decode_register(group: usize, reg: usize, rm: u8) -> Option<Self> {
+        use FpuOperation::*;
+
+        let st = FpuOperand::St(rm);
+        let none = FpuOperand::None;
+        let (op, operands) = match (group, reg, rm) {
+            (0, 2 | 3, _) => (ARITHMETIC[reg], [st, none]),
+            (0, _, _) => (ARITHMETIC[reg], [FpuOperand::St(0), st]),
+            (1, 0, _) => (Fld, [st, none]),
+            (1, 1, _) => (Fxch, [st, none]),
+            (1, 2, 0) => (Fnop, [none, none]),
+            (1, 4, 0) => (Fchs, [none, none]),
+            (1, 4, 1) => (Fabs, [none, none]),
+            (1, 4, 4) => (Ftst, [none, none]),
+            (1, 4, 5) => (Fxam, [none, none]),
+            (1, 5, 0) => (Fld1, [none, none]),
+            (1, 5, 1) => (Fldl2t, [none, none]),
+            (1, 5, 2) => (Fldl2e, [none, none]),
+            (1, 5, 3) => (Fldpi, [none, none]),
+            (1, 5, 4) => (Fldlg2, [none, none]),
+            (1, 5, 5) => (Fldln2, [none, none]),
+            (1, 5, 6) => (Fldz, [none, none]),
+            (1, 6, 0) => (F2xm1, [none, none]),
+            (1, 6, 1) => (Fyl2x, [none, none]),
+            (1, 6, 2) => (Fptan, [none, none]),
+            (1, 6, 3) => (Fpatan, [none, none]),
+            (1, 6, 4) => (Fxtract, [none, none]),
+            (1, 6, 6) => (Fdecstp, [none, none]),
+            (1, 6, 7) => (Fincstp, [none, none]),
+            (1, 7, 0) => (Fprem, [none, none]),
+            (1, 7, 1) => (Fyl2xp1, [none, none]),
+            (1, 7, 2) => (Fsqrt, [none, none]),
+            (1, 7, 4) => (Frndint, [none, none]),
+            (1, 7, 5) => (Fscale, [none, none]),
+            (3, 4, 0) => (Fneni, [none, none]),
+            (3, 4, 1) => (Fndisi, [none, none]),
+            (3, 4, 2) => (Fnclex, [none, none]),
+            (3, 4, 3) => (Fninit, [none, none]),
+            // With ST(i) as the destination the subtract and divide encodings
+            // swap their reversed and plain forms
+            (4, 2 | 3, _) => return None,
+            (4, _, _) => (ARITHMETIC[reg ^ if reg >= 4 { 1 } else { 0 }], [st, FpuOperand::St(0)]),
+            (5, 0, _) => (Ffree, [st, none]),
+            (5, 2, _) => (Fst, [st, none]),
+            (5, 3, _) => (Fstp, [st, none]),
+            (6, 0, _) => (Faddp, [st, FpuOperand::St(0)]),
+            (6, 1, _) => (Fmulp, [st, FpuOperand::St(0)]),
+            (6, 3, 1) => (Fcompp, [none, none]),
+            (6, 4, _) => (Fsubrp, [st, FpuOperand::St(0)]),
+            (6, 5, _) => (Fsubp, [st, FpuOperand::St(0)]),
+            (6, 6, _) => (Fdivrp, [st, FpuOperand::St(0)]),
+            (6, 7, _) => (Fdivp, [st, FpuOperand::St(0)]),
+            _ => return None,
+        };
+
+        Some(Self { op, operands })
+    }
+}
+
+const STATUS_INVALID: u16 = 1 << 0;
+const STATUS_ZERO_DIVIDE: u16 = 1 << 2;
+const STATUS_OVERFLOW: u16 = 1 << 3;
+const STATUS_UNDERFLOW: u16 = 1 << 4;
+const STATUS_PRECISION: u16 = 1 << 5;
+const STATUS_EXCEPTIONS: u16 = 0x3F;
+const STATUS_ERROR_SUMMARY: u16 = 1 << 7;
+const STATUS_C0: u16 = 1 << 8;
+const STATUS_C1: u16 = 1 << 9;
+const STATUS_C2: u16 = 1 << 10;
+const STATUS_C3: u16 = 1 << 14;
+const STATUS_CONDITION: u16 = STATUS_C0 | STATUS_C1 | STATUS_C2 | STATUS_C3;
+const STATUS_BUSY: u16 = 1 << 15;
+const CONTROL_INTERRUPT_MASK: u16 = 1 << 7;
+
+const TAG_VALID: u16 = 0;
+const TAG_ZERO: u16 = 1;
+const TAG_SPECIAL: u16 = 2;
+const TAG_EMPTY: u16 = 3;
+
+fn read_bytes<const N: usize>(memory: &Memory, at: SegmentedAccess) -> [u8; N] {
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let access = SegmentedAccess { segment_offset: at.segment_offset.wrapping_add(i as u16), ..at };
+        *byte = memory.read_value(&access, false) as u8;
+    }
+    bytes
+}
+
+fn write_bytes(memory: &mut Memory, at: SegmentedAccess, bytes: &[u8]) {
+    for (i, byte) in bytes.iter().enumerate() {
+        let access = SegmentedAccess { segment_offset: at.segment_offset.wrapping_add(i as u16), ..at };
+        memory.write_value(&access, false, *byte as u16);
+    }
+}
+
+/// Emulated 8087 numeric coprocessor.
+///
+/// Registers hold full 80-bit values, so loads, stores and integer or BCD
+/// conversions are exact. Addition, subtraction, multiplication, division and
+/// square root work on the 64-bit significands and round under the precision
+/// and rounding control fields. The transcendental functions are computed in
+/// double precision over the full exponent range. Unmasked exceptions store
+/// the same result as masked ones and are reported through the error summary
+/// bit for WAIT to pick up.
+#[derive(Debug, Clone)]
+pub struct Fpu {
+    registers: [F80; 8],
+    pub control: u16,
+    pub status: u16,
+    pub tag: u16,
+    instruction_pointer: u32,
+    opcode: u16,
+    operand_pointer: u32,
+}
+
+impl Default for Fpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fpu {
+    /// A coprocessor in the state FINIT leaves it, as after a hardware reset.
+    pub fn new() -> Self {
+        Self {
+            registers: [F80::ZERO; 8],
+            control: 0x03FF,
+            status: 0,
+            tag: 0xFFFF,
+            instruction_pointer: 0,
+            opcode: 0,
+            operand_pointer: 0,
+        }
+    }
+
+    fn top(&self) -> usize {
+        ((self.status >> 11) & 7) as usize
+    }
+
+    fn set_top(&mut self, top: usize) {
+        self.status = (self.status & !(7 << 11)) | (((top & 7) as u16) << 11);
+    }
+
+    fn physical(&self, i: u8) -> usize {
+        (self.top() + i as usize) & 7
+    }
+
+    fn tag_of(&self, physical: usize) -> u16 {
+        (self.tag >> (physical * 2)) & 3
+    }
+
+    fn set_tag(&mut self, physical: usize, tag: u16) {
+        self.tag = (self.tag & !(3 << (physical * 2))) | (tag << (physical * 2));
+    }
+
+    fn classify(value: F80) -> u16 {
+        if value.is_zero() {
+            TAG_ZERO
+        } else if value.is_unsupported()
+            || value.exponent() == MAX_EXPONENT || value.is_denormal() || (value.significand & INTEGER_BIT) == 0 {
+            TAG_SPECIAL
+        } else {
+            TAG_VALID
+        }
+    }
+
+    /// ST(i), or `None` if that register is empty.
+    pub fn st(&self, i: u8) -> Option<F80> {
+        let physical = self.physical(i);
+        (self.tag_of(physical) != TAG_EMPTY).then_some(self.registers[physical])
+    }
+
+    /// True when an unmasked exception is waiting and interrupts are enabled.
+    pub fn interrupt_pending(&self) -> bool {
+        (self.status & STATUS_ERROR_SUMMARY) != 0 && (self.control & CONTROL_INTERRUPT_MASK) == 0
+    }
+
+    /// Significant bits kept by arithmetic results. The reserved setting
+    /// behaves as extended precision.
+    fn precision(&self) -> u32 {
+        match (self.control >> 8) & 3 {
+            0 => 24,
+            2 => 53,
+            _ => 64,
+        }
+    }
+
+    fn rounding(&self) -> Rounding {
+        match (self.control >> 10) & 3 {
+            0 => Rounding::Nearest,
+            1 => Rounding::Down,
+            2 => Rounding::Up,
+            _ => Rounding::Chop,
+        }
+    }
+
+    fn raise(&mut self, exceptions: u16) {
+        self.status |= exceptions;
+        if (exceptions & !self.control & STATUS_EXCEPTIONS) != 0 {
+            self.status |= STATUS_ERROR_SUMMARY;
+        }
+    }
+
+    /// Raises the invalid operation exception if any of `values` is an
+    /// encoding the 8087 cannot compute with, returning the NaN to use in
+    /// place of the result.
+    fn invalid_operand(&mut self, values: &[F80]) -> Option<F80> {
+        if values.iter().any(|value| value.is_unsupported()) {
+            self.raise(STATUS_INVALID);
+            Some(F80::INDEFINITE)
+        } else {
+            None
+        }
+    }
+
+    fn read_st(&mut self, i: u8) -> F80 {
+        match self.st(i) {
+            Some(value) => value,
+            None => {
+                // Stack underflow
+                self.raise(STATUS_INVALID);
+                F80::INDEFINITE
+            }
+        }
+    }
+
+    fn write_st(&mut self, i: u8, value: F80) {
+        let physical = self.physical(i);
+        self.registers[physical] = value;
+        self.set_tag(physical, Self::classify(value));
+    }
+
+    fn push(&mut self, value: F80) {
+        let top = (self.top() + 7) & 7;
+        self.set_top(top);
+        let value = if self.tag_of(top) != TAG_EMPTY {
+            // Stack overflow
+            self.raise(STATUS_INVALID);
+            F80::INDEFINITE
+        } else {
+            value
+        };
+        self.write_st(0, value);
+    }
+
+    fn pop(&mut self) {
+        let top = self.top();
+        self.set_tag(top, TAG_EMPTY);
+        self.set_top(top + 1);
+    }
+
+    /// Rounds `magnitude` * 2^`power` to `bits` significant bits under the
+    /// rounding control and raises whatever that costs.
+    fn round(&mut self, negative: bool, magnitude: u128, power: i32, bits: u32) -> F80 {
+        let (result, exceptions) = F80::round(negative, magnitude, power, bits, self.rounding());
+        self.raise(exceptions);
+        result
+    }
+
+    /// Multiplies `value` by 2^`delta` at full precision, flagging overflow
+    /// and underflow of the extended exponent range.
+    fn rescale(&mut self, value: F80, delta: i32) -> F80 {
+        if !value.is_finite_nonzero() {
+            return value;
+        }
+        let (negative, power, significand) = value.unpack();
+        self.round(negative, significand as u128, power - 63 + delta, 64)
+    }
+
+    fn arithmetic(&mut self, op: Arithmetic, a: F80, b: F80) -> F80 {
+        if let Some(nan) = self.invalid_operand(&[a, b]) {
+            return nan;
+        }
+        if a.is_nan() || b.is_nan() {
+            return if a.is_nan() { a } else { b };
+        }
+
+        // Reversed forms are the plain ones with the operands swapped
+        let (a, b, op) = match op {
+            Arithmetic::Subr => (b, a, Arithmetic::Sub),
+            Arithmetic::Divr => (b, a, Arithmetic::Div),
+            _ => (a, b, op),
+        };
+
+        let b = if op == Arithmetic::Sub { b.negate() } else { b };
+        let precision = self.precision();
+
+        if a.is_finite_nonzero() && b.is_finite_nonzero() {
+            let (na, pa, sa) = a.unpack();
+            let (nb, pb, sb) = b.unpack();
+            let (negative, magnitude, power) = match op {
+                Arithmetic::Mul => (na != nb, sa as u128 * sb as u128, pa + pb - 126),
+                Arithmetic::Div => {
+                    // Two long division steps give 65 + 62 quotient bits,
+                    // with the final remainder folded into the lowest one
+                    let (divisor, high) = (sb as u128, (sa as u128) << 64);
+                    let remainder = high % divisor;
+                    let low = remainder << 62;
+                    let quotient = ((high / divisor) << 62) | (low / divisor);
+                    (na != nb, quotient | ((low % divisor) != 0) as u128, pa - pb - 126)
+                }
+                _ => {
+                    // Align on the larger operand with 63 guard bits below its
+                    // significand; anything shifted out past them is sticky
+                    let (first, second) = ((na, pa, sa), (nb, pb, sb));
+                    let ((na, pa, sa), (nb, pb, sb)) = if pa >= pb { (first, second) } else { (second, first) };
+                    let (big, small) = ((sa as u128) << 63, (sb as u128) << 63);
+                    let gap = (pa - pb) as u32;
+                    let small = if gap >= 128 {
+                        1
+                    } else {
+                        (small >> gap) | ((small & ((1 << gap) - 1)) != 0) as u128
+                    };
+                    if na == nb {
+                        (na, big + small, pa - 126)
+                    } else if big >= small {
+                        (na, big - small, pa - 126)
+                    } else {
+                        (nb, small - big, pa - 126)
+                    }
+                }
+            };
+            if magnitude == 0 {
+                // Exact cancellation
+                return F80::ZERO.with_sign(self.rounding() == Rounding::Down);
+            }
+            return self.round(negative, magnitude, power, precision);
+        }
+
+        // Next to zeros and infinities only the sign of a finite operand
+        // matters, except that adding zero leaves the other operand as it is
+        if matches!(op, Arithmetic::Add | Arithmetic::Sub) {
+            let unchanged = match (a.is_zero(), b.is_zero()) {
+                (false, true) if a.is_finite_nonzero() => Some(a),
+                (true, false) if b.is_finite_nonzero() => Some(b),
+                (true, true) if a.is_negative() != b.is_negative() => {
+                    return F80::ZERO.with_sign(self.rounding() == Rounding::Down);
+                }
+                (true, true) => return a,
+                _ => None,
+            };
+            if let Some(value) = unchanged {
+                let (negative, power, significand) = value.unpack();
+                return self.round(negative, significand as u128, power - 63, precision);
+            }
+        }
+
+        let sign_only = |v: F80| {
+            if v.is_finite_nonzero() {
+                if v.is_negative() { -1.0 } else { 1.0 }
+            } else {
+                v.to_f64()
+            }
+        };
+        let (x, y) = (sign_only(a), sign_only(b));
+        let result = match op {
+            Arithmetic::Add | Arithmetic::Sub => x + y,
+            Arithmetic::Mul => x * y,
+            _ => x / y,
+        };
+
+        if result.is_nan() {
+            self.raise(STATUS_INVALID);
+            return F80::INDEFINITE;
+        }
+        if op == Arithmetic::Div && b.is_zero() && a.is_finite_nonzero() {
+            self.raise(STATUS_ZERO_DIVIDE);
+        }
+        F80::from_f64(result)
+    }
+
+    /// Applies a function of one double to ST(0)-sized operands that the 8087
+    /// only defines over a small domain.
+    fn transcendental(&mut self, value: f64) -> F80 {
+        if value.is_nan() {
+            self.raise(STATUS_INVALID);
+            return F80::INDEFINITE;
+        }
+        self.raise(STATUS_PRECISION);
+        F80::from_f64(value)
+    }
+
+    fn compare(&mut self, a: F80, b: F80) {
+        let condition = match a.compare(b) {
+            Some(Ordering::Greater) => 0,
+            Some(Ordering::Less) => STATUS_C0,
+            Some(Ordering::Equal) => STATUS_C3,
+            None => {
+                self.raise(STATUS_INVALID);
+                STATUS_C3 | STATUS_C2 | STATUS_C0
+            }
+        };
+        self.status = (self.status & !STATUS_CONDITION) | condition;
+    }
+
+    fn examine(&mut self) {
+        let value = self.registers[self.top()];
+        let condition = if self.st(0).is_none() {
+            STATUS_C3 | STATUS_C0
+        } else if value.is_nan() {
+            STATUS_C0
+        } else if value.is_infinite() {
+            STATUS_C2 | STATUS_C0
+        } else if value.is_zero() {
+            STATUS_C3
+        } else if value.is_denormal() {
+            STATUS_C3 | STATUS_C2
+        } else if (value.significand & INTEGER_BIT) == 0 {
+            0
+        } else {
+            STATUS_C2
+        };
+        let sign = if value.is_negative() { STATUS_C1 } else { 0 };
+        self.status = (self.status & !STATUS_CONDITION) | condition | sign;
+    }
+
+    fn load(&mut self, memory: &Memory, reg_file: &RegisterFile, operand: &FpuOperand) -> F80 {
+        let (address, format) = match operand {
+            FpuOperand::St(i) => return self.read_st(*i),
+            FpuOperand::Memory(address, format) => (address, format),
+            FpuOperand::None => return F80::INDEFINITE,
+        };
+        let at = effective_address(reg_file, address);
+
+        match format {
+            MemoryFormat::Real32 => F80::from_f64(f32::from_le_bytes(read_bytes(memory, at)) as f64),
+            MemoryFormat::Real64 => F80::from_f64(f64::from_le_bytes(read_bytes(memory, at))),
+            MemoryFormat::Real80 => F80::from_bytes(read_bytes(memory, at)),
+            MemoryFormat::Int16 => F80::from_i64(i16::from_le_bytes(read_bytes(memory, at)) as i64),
+            MemoryFormat::Int32 => F80::from_i64(i32::from_le_bytes(read_bytes(memory, at)) as i64),
+            MemoryFormat::Int64 => F80::from_i64(i64::from_le_bytes(read_bytes(memory, at))),
+            MemoryFormat::Bcd => {
+                let bytes: [u8; 10] = read_bytes(memory, at);
+                let magnitude = bytes[..9]
+                    .iter()
+                    .rev()
+                    .fold(0u64, |acc, byte| acc * 100 + (byte >> 4) as u64 * 10 + (byte & 0xF) as u64);
+                F80::from_magnitude((bytes[9] & 0x80) != 0, magnitude)
+            }
+            MemoryFormat::Word | MemoryFormat::Environment | MemoryFormat::State => F80::INDEFINITE,
+        }
+    }
+
+    fn store(&mut self, memory: &mut Memory, reg_file: &RegisterFile, operand: &FpuOperand, value: F80) {
+        let (address, format) = match operand {
+            FpuOperand::St(i) => return self.write_st(*i, value),
+            FpuOperand::Memory(address, format) => (address, format),
+            FpuOperand::None => return,
+        };
+        let at = effective_address(reg_file, address);
+        let value = match format {
+            MemoryFormat::Real80 => value,
+            _ => self.invalid_operand(&[value]).unwrap_or(value),
+        };
+
+        let real = |fpu: &mut Self, bits: u32, exponent_bits: u32| -> u64 {
+            let (real, exceptions) = value.to_real(bits, exponent_bits, fpu.rounding());
+            fpu.raise(exceptions);
+            real
+        };
+
+        let integer = |fpu: &mut Self, bits: u32| -> i128 {
+            let limit = 1i128 << (bits - 1);
+            match value.round_to_integer(fpu.rounding()) {
+                Some(integer) if (-limit..limit).contains(&integer) => integer,
+                _ => {
+                    fpu.raise(STATUS_INVALID);
+                    -limit
+                }
+            }
+        };
+
+        match format {
+            MemoryFormat::Real32 => write_bytes(memory, at, &(real(self, 24, 8) as u32).to_le_bytes()),
+            MemoryFormat::Real64 => write_bytes(memory, at, &real(self, 53, 11).to_le_bytes()),
+            MemoryFormat::Real80 => write_bytes(memory, at, &value.to_bytes()),
+            MemoryFormat::Int16 => write_bytes(memory, at, &(integer(self, 16) as i16).to_le_bytes()),
+            MemoryFormat::Int32 => write_bytes(memory, at, &(integer(self, 32) as i32).to_le_bytes()),
+            MemoryFormat::Int64 => write_bytes(memory, at, &(integer(self, 64) as i64).to_le_bytes()),
+            MemoryFormat::Bcd => {
+                let mut bytes = [0u8; 10];
+                match value.round_to_integer(self.rounding()) {
+                    Some(integer) if integer.unsigned_abs() < 1_000_000_000_000_000_000 => {
+                        let mut magnitude = integer.unsigned_abs();
+                        for byte in bytes.iter_mut().take(9) {
+                            *byte = ((magnitude % 10) | ((magnitude / 10 % 10) << 4)) as u8;
+                            magnitude /= 100;
+                        }
+                        bytes[9] = if value.is_negative() { 0x80 } else { 0 };
+                    }
+                    _ => {
+                        // Packed decimal indefinite
+                        self.raise(STATUS_INVALID);
+                        bytes[7] = 0xC0;
+                        bytes[8] = 0xFF;
+                        bytes[9] = 0xFF;
+                    }
+                }
+                write_bytes(memory, at, &bytes);
+            }
+            MemoryFormat::Word | MemoryFormat::Environment | MemoryFormat::State => {}
+        }
+    }
+
+    fn store_environment(&self, memory: &mut Memory, at: SegmentedAccess) {
+        let words = [
+            self.control,
+            self.status,
+            self.tag,
+            self.instruction_pointer as u16,
+            (((self.instruction_pointer >> 16) as u16) << 12) | (self.opcode & 0x7FF),
+            self.operand_pointer as u16,
+            ((self.operand_pointer >> 16) as u16) << 12,
+        ];
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        write_bytes(memory, at, &bytes);
+    }
+
+    fn load_environment(&mut self, memory: &Memory, at: SegmentedAccess) {
+        let bytes: [u8; 14] = read_bytes(memory, at);
+        let word = |i: usize| u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+        self.control = word(0);
+        self.status = word(1);
+        self.tag = word(2);
+        self.instruction_pointer = word(3) as u32 | (((word(4) >> 12) as u32) << 16);
+        self.opcode = word(4) & 0x7FF;
+        self.operand_pointer = word(5) as u32 | (((word(6) >> 12) as u32) << 16);
+    }
+
+    /// Executes an ESC instruction. Encodings the 8087 does not define are
+    /// ignored, as the chip ignores them.
+    pub fn execute(&mut self, instruction: &Instruction, memory: &mut Memory, reg_file: &RegisterFile) {
+        use FpuOperation::*;
+
+        let Some(fpu_instruction) = FpuInstruction::decode(instruction) else {
+            return;
+        };
+        let op = fpu_instruction.op;
+        let [dst, src] = fpu_instruction.operands;
+        let address = match dst {
+            FpuOperand::Memory(address, _) => Some(effective_address(reg_file, &address)),
+            _ => None,
+        };
+
+        if !op.is_control() {
+            self.instruction_pointer = instruction.address;
+            if let Operand::Immediate(opcode) = instruction.operands[0] {
+                self.opcode = ((opcode as u16 & 0x38) << 5) | ((opcode as u16 & 7) << 3);
+            }
+            if let Some(at) = address {
+                self.operand_pointer = at.get_absolute_address(0);
+            }
+        }
+
+        if let Some(arithmetic) = op.arithmetic() {
+            let (index, a, b) = match (dst, src) {
+                (FpuOperand::St(i), FpuOperand::St(j)) => (i, self.read_st(i), self.read_st(j)),
+                _ => (0, self.read_st(0), self.load(memory, reg_file, &dst)),
+            };
+            let result = self.arithmetic(arithmetic, a, b);
+            self.write_st(index, result);
+            if matches!(op, Faddp | Fmulp | Fsubp | Fsubrp | Fdivp | Fdivrp) {
+                self.pop();
+            }
+            return;
+        }
+
+        match op {
+            Fcom | Fcomp | Ficom | Ficomp | Fcompp => {
+                let a = self.read_st(0);
+                let b = match dst {
+                    FpuOperand::None => self.read_st(1),
+                    _ => self.load(memory, reg_file, &dst),
+                };
+                self.compare(a, b);
+                let pops = match op {
+                    Fcompp => 2,
+                    Fcomp | Ficomp => 1,
+                    _ => 0,
+                };
+                for _ in 0..pops {
+                    self.pop();
+                }
+            }
+            Ftst => {
+                let a = self.read_st(0);
+                self.compare(a, F80::ZERO);
+            }
+            Fxam => self.examine(),
+
+            Fld | Fild | Fbld => {
+                let value = self.load(memory, reg_file, &dst);
+                self.push(value);
+            }
+            Fst | Fstp | Fist | Fistp | Fbstp => {
+                let value = self.read_st(0);
+                self.store(memory, reg_file, &dst, value);
+                if matches!(op, Fstp | Fistp | Fbstp) {
+                    self.pop();
+                }
+            }
+            Fxch => {
+                if let FpuOperand::St(i) = dst {
+                    let (a, b) = (self.read_st(0), self.read_st(i));
+                    self.write_st(0, b);
+                    self.write_st(i, a);
+                }
+            }
+            Ffree => {
+                if let FpuOperand::St(i) = dst {
+                    let physical = self.physical(i);
+                    self.set_tag(physical, TAG_EMPTY);
+                }
+            }
+
+            Fld1 => self.push(F80::ONE),
+            Fldz => self.push(F80::ZERO),
+            Fldpi => self.push(F80 { significand: 0xC90F_DAA2_2168_C235, sign_exponent: 0x4000 }),
+            Fldl2t => self.push(F80 { significand: 0xD49A_784B_CD1B_8AFE, sign_exponent: 0x4000 }),
+            Fldl2e => self.push(F80 { significand: 0xB8AA_3B29_5C17_F0BC, sign_exponent: 0x3FFF }),
+            Fldlg2 => self.push(F80 { significand: 0x9A20_9A84_FBCF_F799, sign_exponent: 0x3FFD }),
+            Fldln2 => self.push(F80 { significand: 0xB172_17F7_D1CF_79AC, sign_exponent: 0x3FFE }),
+
+            Fchs => {
+                let value = self.read_st(0);
+                self.write_st(0, value.negate());
+            }
+            Fabs => {
+                let value = self.read_st(0);
+                self.write_st(0, value.with_sign(false));
+            }
+            Fsqrt => {
+                let value = self.read_st(0);
+                let result = if let Some(nan) = self.invalid_operand(&[value]) {
+                    nan
+                } else if value.is_negative() && !value.is_zero() && !value.is_nan() {
+                    self.raise(STATUS_INVALID);
+                    F80::INDEFINITE
+                } else if value.is_finite_nonzero() {
+                    // Widen to 127 or 128 bits so the power left over is even;
+                    // the integer root then has 64 bits, and comparing the
+                    // remainder with the root gives the half bit exactly
+                    let (_, power, significand) = value.unpack();
+                    let widen = if (power - 63) % 2 == 0 { 64 } else { 63 };
+                    let square = (significand as u128) << widen;
+                    let root = square.isqrt();
+                    let remainder = square - root * root;
+                    let magnitude = (root << 2) | (((remainder > root) as u128) << 1) | (remainder != 0) as u128;
+                    self.round(false, magnitude, (power - 63 - widen) / 2 - 2, self.precision())
+                } else {
+                    value
+                };
+                self.write_st(0, result);
+            }
+            Frndint => {
+                let value = self.read_st(0);
+                let result = match value.round_to_integer(self.rounding()) {
+                    _ if value.is_unsupported() => self.invalid_operand(&[value]).unwrap_or(value),
+                    Some(_) if value.exponent() as i32 - EXPONENT_BIAS >= 63 => value,
+                    Some(integer) => F80::from_magnitude(value.is_negative(), integer.unsigned_abs() as u64),
+                    None => value,
+                };
+                self.write_st(0, result);
+            }
+            Fscale => {
+                let (value, factor) = (self.read_st(0), self.read_st(1));
+                let result = match self.invalid_operand(&[value, factor]) {
+                    Some(nan) => nan,
+                    None => {
+                        let power = factor.round_to_integer(Rounding::Chop).unwrap_or(0).clamp(-0x10000, 0x10000);
+                        self.rescale(value, power as i32)
+                    }
+                };
+                self.write_st(0, result);
+            }
+            Fxtract => {
+                let value = self.read_st(0);
+                let value = self.invalid_operand(&[value]).unwrap_or(value);
+                if value.is_finite_nonzero() {
+                    let exponent = value.exponent().max(1) as i32 - EXPONENT_BIAS;
+                    let significand = F80 { significand: value.significand, sign_exponent: EXPONENT_BIAS as u16 }
+                        .with_sign(value.is_negative());
+                    self.write_st(0, F80::from_i64(exponent as i64));
+                    self.push(significand);
+                } else {
+                    self.write_st(0, value);
+                    self.push(value);
+                }
+            }
+            Fprem => {
+                let (a, b) = (self.read_st(0), self.read_st(1));
+                if let Some(nan) = self.invalid_operand(&[a, b]) {
+                    self.write_st(0, nan);
+                    self.status &= !STATUS_C2;
+                    return;
+                }
+                if !a.is_finite_nonzero() || !b.is_finite_nonzero() {
+                    if a.is_infinite() || b.is_zero() || a.is_nan() || b.is_nan() {
+                        self.raise(STATUS_INVALID);
+                        self.write_st(0, F80::INDEFINITE);
+                    }
+                    self.status &= !STATUS_C2;
+                    return;
+                }
+
+                // Exact remainder on the integer significands. A large exponent
+                // gap is only partly reduced and C2 asks for another pass.
+                let ea = a.exponent().max(1) as i32;
+                let eb = b.exponent().max(1) as i32;
+                let gap = ea - eb;
+                if gap < 0 {
+                    self.status &= !STATUS_CONDITION;
+                    return;
+                }
+                let partial = gap >= 64;
+                let shift = if partial { 63 } else { gap };
+                let dividend = (a.significand as u128) << shift;
+                let quotient = dividend / b.significand as u128;
+                let remainder = (dividend % b.significand as u128) as u64;
+
+                let result = F80::from_magnitude(a.is_negative(), remainder);
+                let result = if remainder == 0 {
+                    result
+                } else {
+                    let delta = ea - shift - (EXPONENT_BIAS + 63);
+                    self.rescale(result, delta)
+                };
+                self.write_st(0, result);
+
+                let mut condition = if partial { STATUS_C2 } else { 0 };
+                if !partial {
+                    if (quotient & 4) != 0 {
+                        condition |= STATUS_C0;
+                    }
+                    if (quotient & 2) != 0 {
+                        condition |= STATUS_C1;
+                    }
+                    if (quotient & 1) != 0 {
+                        condition |= STATUS_C3;
+                    }
+                }
+                self.status = (self.status & !STATUS_CONDITION) | condition;
+            }
+
+            F2xm1 => {
+                let x = self.read_st(0);
+                let result = match self.invalid_operand(&[x]) {
+                    Some(nan) => nan,
+                    None => self.transcendental(x.to_f64().exp2() - 1.0),
+                };
+                self.write_st(0, result);
+            }
+            Fyl2x | Fyl2xp1 => {
+                let (x, y) = (self.read_st(0), self.read_st(1));
+                let result = if let Some(nan) = self.invalid_operand(&[x, y]) {
+                    nan
+                } else {
+                    let log = match x.split() {
+                        Some((mantissa, exponent)) if op == Fyl2x && !x.is_negative() => {
+                            mantissa.log2() + exponent as f64
+                        }
+                        _ if op == Fyl2x => x.to_f64().log2(),
+                        _ => x.to_f64().ln_1p() / std::f64::consts::LN_2,
+                    };
+                    let log = self.transcendental(log);
+                    self.arithmetic(Arithmetic::Mul, y, log)
+                };
+                self.pop();
+                self.write_st(0, result);
+            }
+            Fptan => {
+                let x = self.read_st(0);
+                let result = match self.invalid_operand(&[x]) {
+                    Some(nan) => nan,
+                    None => self.transcendental(x.to_f64().tan()),
+                };
+                self.write_st(0, result);
+                self.push(F80::ONE);
+            }
+            Fpatan => {
+                let (x, y) = (self.read_st(0), self.read_st(1));
+                let result = match (self.invalid_operand(&[x, y]), x.split(), y.split()) {
+                    (Some(nan), _, _) => nan,
+                    (None, Some((mx, ex)), Some((my, ey))) => self.transcendental(scale(my, ey - ex).atan2(mx)),
+                    (None, _, _) => self.transcendental(y.to_f64().atan2(x.to_f64())),
+                };
+                self.pop();
+                self.write_st(0, result);
+            }
+
+            Fincstp => {
+                let top = self.top();
+                self.set_top(top + 1);
+            }
+            Fdecstp => {
+                let top = self.top();
+                self.set_top(top + 7);
+            }
+            Fnop => {}
+
+            Fldcw => {
+                if let Some(at) = address {
+                    self.control = memory.read_value(&at, true);
+                }
+            }
+            Fnstcw => {
+                if let Some(at) = address {
+                    memory.write_value(&at, true, self.control);
+                }
+            }
+            Fnstsw => {
+                if let Some(at) = address {
+                    memory.write_value(&at, true, self.status);
+                }
+            }
+            Fldenv => {
+                if let Some(at) = address {
+                    self.load_environment(memory, at);
+                }
+            }
+            Fnstenv => {
+                if let Some(at) = address {
+                    self.store_environment(memory, at);
+                    // Storing the environment masks all exceptions
+                    self.control |= STATUS_EXCEPTIONS | CONTROL_INTERRUPT_MASK;
+                }
+            }
+            Fnsave => {
+                if let Some(at) = address {
+                    self.store_environment(memory, at);
+                    for i in 0..8u8 {
+                        let value = self.registers[self.physical(i)];
+                        let at = SegmentedAccess { segment_offset: at.segment_offset.wrapping_add(14 + i as u16 * 10), ..at };
+                        write_bytes(memory, at, &value.to_bytes());
+                    }
+                    *self = Self::new();
+                }
+            }
+            Frstor => {
+                if let Some(at) = address {
+                    self.load_environment(memory, at);
+                    for i in 0..8u8 {
+                        let at = SegmentedAccess { segment_offset: at.segment_offset.wrapping_add(14 + i as u16 * 10), ..at };
+                        let physical = self.physical(i);
+                        self.registers[physical] = F80::from_bytes(read_bytes(memory, at));
+                    }
+                }
+            }
+
+            Fneni => self.control &= !CONTROL_INTERRUPT_MASK,
+            Fndisi => self.control |= CONTROL_INTERRUPT_MASK,
+            Fnclex => self.status &= !(STATUS_EXCEPTIONS | STATUS_ERROR_SUMMARY | STATUS_BUSY),
+            Fninit => *self = Self::new(),
+
+            _ => {}
+        }
+    }
+
+    pub fn print_state(&self) {
+        println!("\nFPU:");
+        println!("CW={:04x}  SW={:04x}  TW={:04x}", self.control, self.status, self.tag);
+        for i in 0..8u8 {
+            if let Some(value) = self.st(i) {
+                println!("ST{}={}", i, value.to_f64());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{Simulator, StopReason};
+
+    /// Runs `code` to its HLT with `data` at 0000:0100 and returns the
+    /// simulator for inspection.
+    fn run(code: &[u8], data: &[u8]) -> Simulator {
+        let mut simulator = Simulator::with_code(code);
+        simulator.fpu = Some(Fpu::new());
+        simulator.load(0x100, data);
+        assert_eq!(simulator.run(Some(1000)), StopReason::Halted);
+        simulator
+    }
+
+    fn read_f80(simulator: &Simulator, address: u32) -> F80 {
+        F80::from_bytes(std::array::from_fn(|i| simulator.memory.bytes[address as usize + i]))
+    }
+
+    #[test]
+    fn pseudo_zeros_are_invalid_operands() {
+        let pseudo_zero = F80 { significand: 0, sign_exponent: 0x3FFF };
+        // fld tword [0100h]; fld1; fprem; fcom st1; fstp tword [0200h];
+        // fnstsw [0300h]; hlt
+        let code = [
+            0xDB, 0x2E, 0x00, 0x01, 0xD9, 0xE8, 0xD9, 0xF8, 0xD8, 0xD1, 0xDB, 0x3E, 0x00, 0x02, 0xDD, 0x3E, 0x00,
+            0x03, 0xF4,
+        ];
+        let simulator = run(&code, &pseudo_zero.to_bytes());
+
+        assert_eq!(read_f80(&simulator, 0x200), F80::INDEFINITE);
+        let status = simulator.read_word(0x300);
+        assert_ne!(status & STATUS_INVALID, 0);
+        assert_eq!(status & STATUS_CONDITION, STATUS_C3 | STATUS_C2 | STATUS_C0);
+    }
+
+    #[test]
+    fn division_keeps_all_64_bits() {
+        // fld1; fild word [0100h]; fdivp st1, st0; fstp tword [0200h]; hlt
+        let code = [0xD9, 0xE8, 0xDF, 0x06, 0x00, 0x01, 0xDE, 0xF9, 0xDB, 0x3E, 0x00, 0x02, 0xF4];
+        let simulator = run(&code, &3u16.to_le_bytes());
+        assert_eq!(read_f80(&simulator, 0x200), F80 { significand: 0xAAAA_AAAA_AAAA_AAAB, sign_exponent: 0x3FFD });
+    }
+
+    #[test]
+    fn precision_and_rounding_control_shape_one_third() {
+        let cases = [
+            (0x00FF, 0xAAAA_AB00_0000_0000),
+            (0x02FF, 0xAAAA_AAAA_AAAA_A800),
+            (0x03FF, 0xAAAA_AAAA_AAAA_AAAB),
+            (0x07FF, 0xAAAA_AAAA_AAAA_AAAA),
+            (0x0BFF, 0xAAAA_AAAA_AAAA_AAAB),
+            (0x0FFF, 0xAAAA_AAAA_AAAA_AAAA),
+        ];
+        for (control, significand) in cases {
+            // fldcw [0102h]; fld1; fild word [0100h]; fdivp st1, st0;
+            // fstp tword [0200h]; hlt
+            let code = [
+                0xD9, 0x2E, 0x02, 0x01, 0xD9, 0xE8, 0xDF, 0x06, 0x00, 0x01, 0xDE, 0xF9, 0xDB, 0x3E, 0x00, 0x02, 0xF4,
+            ];
+            let data = [3, 0, control as u8, (control >> 8) as u8];
+            let simulator = run(&code, &data);
+            assert_eq!(read_f80(&simulator, 0x200).significand, significand, "control {control:04x}");
+        }
+    }
+
+    #[test]
+    fn integers_beyond_53_bits_survive_multiplication() {
+        // fild qword [0100h]; fld1; fmulp st1, st0; fistp qword [0200h]; hlt
+        let code = [0xDF, 0x2E, 0x00, 0x01, 0xD9, 0xE8, 0xDE, 0xC9, 0xDF, 0x3E, 0x00, 0x02, 0xF4];
+        let value = (1i64 << 62) + 1;
+        let simulator = run(&code, &value.to_le_bytes());
+        let stored: [u8; 8] = std::array::from_fn(|i| simulator.memory.bytes[0x200 + i]);
+        assert_eq!(i64::from_le_bytes(stored), value);
+    }
+
+    #[test]
+    fn square_root_and_scale_keep_all_64_bits() {
+        // fild word [0100h]; fsqrt; fstp tword [0200h]; hlt
+        let code = [0xDF, 0x06, 0x00, 0x01, 0xD9, 0xFA, 0xDB, 0x3E, 0x00, 0x02, 0xF4];
+        let simulator = run(&code, &2u16.to_le_bytes());
+        assert_eq!(read_f80(&simulator, 0x200), F80 { significand: 0xB504_F333_F9DE_6484, sign_exponent: 0x3FFF });
+
+        // fild word [0108h]; fild qword [0100h]; fscale; fistp qword [0200h]; hlt
+        let code = [0xDF, 0x06, 0x08, 0x01, 0xDF, 0x2E, 0x00, 0x01, 0xD9, 0xFD, 0xDF, 0x3E, 0x00, 0x02, 0xF4];
+        let mut data = ((1i64 << 61) + 1).to_le_bytes().to_vec();
+        data.extend(1u16.to_le_bytes());
+        let simulator = run(&code, &data);
+        let stored: [u8; 8] = std::array::from_fn(|i| simulator.memory.bytes[0x200 + i]);
+        assert_eq!(i64::from_le_bytes(stored), (1 << 62) + 2);
+    }
+
+    #[test]
+    fn rounding_breaks_ties_to_even_and_overflows_by_mode() {
+        let (tie, exceptions) = F80::round(false, (1 << 64) + 1, 0, 64, Rounding::Nearest);
+        assert_eq!(tie, F80 { significand: INTEGER_BIT, sign_exponent: 0x3FFF + 64 });
+        assert_eq!(exceptions, STATUS_PRECISION);
+        let (tie, _) = F80::round(false, (1 << 64) + 3, 0, 64, Rounding::Nearest);
+        assert_eq!(tie.significand, INTEGER_BIT + 2);
+
+        let (largest, exceptions) = F80::round(false, 3, 0x4000, 64, Rounding::Chop);
+        assert_eq!(largest, F80 { significand: u64::MAX, sign_exponent: MAX_EXPONENT - 1 });
+        assert_eq!(exceptions, STATUS_OVERFLOW | STATUS_PRECISION);
+        let (infinity, _) = F80::round(true, 3, 0x4000, 64, Rounding::Nearest);
+        assert_eq!(infinity, F80 { significand: INTEGER_BIT, sign_exponent: 0xFFFF });
+    }
+
+    #[test]
+    fn single_and_double_stores_round_once_under_the_rounding_control() {
+        let inexact = F80 { significand: INTEGER_BIT | 1 << 39 | 1 << 3, sign_exponent: 0x3FFF };
+        let tiny = F80 { significand: 0xC000_0000_0000_0000, sign_exponent: 0x3FFF - 1074 };
+        let huge = F80 { significand: INTEGER_BIT, sign_exponent: 0x3FFF + 200 };
+        let (nearest, up, chop) = (0x033F, 0x0B3F, 0x0F3F);
+        let cases = [
+            (inexact, nearest, 0x3F80_0001, 0x3FF0_0000_1000_0000, STATUS_PRECISION),
+            (inexact, up, 0x3F80_0001, 0x3FF0_0000_1000_0001, STATUS_PRECISION),
+            (inexact, chop, 0x3F80_0000, 0x3FF0_0000_1000_0000, STATUS_PRECISION),
+            (tiny, nearest, 0x0000_0000, 0x0000_0000_0000_0002, STATUS_UNDERFLOW | STATUS_PRECISION),
+            (tiny, up, 0x0000_0001, 0x0000_0000_0000_0002, STATUS_UNDERFLOW | STATUS_PRECISION),
+            (tiny, chop, 0x0000_0000, 0x0000_0000_0000_0001, STATUS_UNDERFLOW | STATUS_PRECISION),
+            (huge, nearest, 0x7F80_0000, 0x4C70_0000_0000_0000, STATUS_OVERFLOW | STATUS_PRECISION),
+            (huge, chop, 0x7F7F_FFFF, 0x4C70_0000_0000_0000, STATUS_OVERFLOW | STATUS_PRECISION),
+            (huge.negate(), up, 0xFF7F_FFFF, 0xCC70_0000_0000_0000, STATUS_OVERFLOW | STATUS_PRECISION),
+        ];
+        for (value, control, single, double, exceptions) in cases {
+            // fldcw [010Ah]; fld tword [0100h]; fst dword [0200h];
+            // fstp qword [0208h]; fnstsw [0300h]; hlt
+            let code = [
+                0xD9, 0x2E, 0x0A, 0x01, 0xDB, 0x2E, 0x00, 0x01, 0xD9, 0x16, 0x00, 0x02, 0xDD, 0x1E, 0x08, 0x02, 0xDD,
+                0x3E, 0x00, 0x03, 0xF4,
+            ];
+            let mut data = value.to_bytes().to_vec();
+            data.extend((control as u16).to_le_bytes());
+            let simulator = run(&code, &data);
+
+            let bytes = &simulator.memory.bytes[0x200..0x210];
+            let stored_single = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+            let stored_double = u64::from_le_bytes(bytes[8..].try_into().unwrap());
+            assert_eq!((stored_single, stored_double), (single, double), "{value:?} under {control:04x}");
+            assert_eq!(simulator.read_word(0x300) & STATUS_EXCEPTIONS, exceptions, "{value:?} under {control:04x}");
+        }
+    }
+}
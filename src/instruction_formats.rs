@@ -1,3 +1,5 @@
+use std::sync::LazyLock;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u32)]
 pub enum OperationType {
@@ -220,6 +222,7 @@ pub enum InstructionBitsUsage {
     W,
     V,
     Z,
+    Far,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -780,7 +783,7 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             op: OperationType::Aam,
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b11010100 },
-                InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b00001010 },
+                InstructionBits { usage: InstructionBitsUsage::HasData, bit_count: 0, shift: 0, value: 1 }, // base, 10 for decimal
             ],
         },
         InstructionFormat {
@@ -809,7 +812,7 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             op: OperationType::Aad,
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b11010101 },
-                InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b00001010 },
+                InstructionBits { usage: InstructionBitsUsage::HasData, bit_count: 0, shift: 0, value: 1 }, // base, 10 for decimal
             ],
         },
         InstructionFormat {
@@ -1101,6 +1104,8 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b11101000 },
                 InstructionBits { usage: InstructionBitsUsage::HasDisp, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::DispAlwaysW, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::RelJmpDisp, bit_count: 0, shift: 0, value: 1 },
             ],
         },
         InstructionFormat {
@@ -1118,8 +1123,11 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b10011010 },
                 InstructionBits { usage: InstructionBitsUsage::HasDisp, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::DispAlwaysW, bit_count: 0, shift: 0, value: 1 },
                 InstructionBits { usage: InstructionBitsUsage::HasData, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::WMakesDataW, bit_count: 0, shift: 0, value: 1 },
                 InstructionBits { usage: InstructionBitsUsage::W, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::Far, bit_count: 0, shift: 0, value: 1 },
             ],
         },
         InstructionFormat {
@@ -1130,6 +1138,7 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 3, shift: 0, value: 0b011 },
                 InstructionBits { usage: InstructionBitsUsage::Rm, bit_count: 3, shift: 0, value: 0 },
                 InstructionBits { usage: InstructionBitsUsage::W, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::Far, bit_count: 0, shift: 0, value: 1 },
             ],
         },
 
@@ -1139,6 +1148,8 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b11101001 },
                 InstructionBits { usage: InstructionBitsUsage::HasDisp, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::DispAlwaysW, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::RelJmpDisp, bit_count: 0, shift: 0, value: 1 },
             ],
         },
         InstructionFormat {
@@ -1146,6 +1157,7 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b11101011 },
                 InstructionBits { usage: InstructionBitsUsage::HasDisp, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::RelJmpDisp, bit_count: 0, shift: 0, value: 1 },
             ],
         },
         InstructionFormat {
@@ -1163,8 +1175,11 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b11101010 },
                 InstructionBits { usage: InstructionBitsUsage::HasDisp, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::DispAlwaysW, bit_count: 0, shift: 0, value: 1 },
                 InstructionBits { usage: InstructionBitsUsage::HasData, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::WMakesDataW, bit_count: 0, shift: 0, value: 1 },
                 InstructionBits { usage: InstructionBitsUsage::W, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::Far, bit_count: 0, shift: 0, value: 1 },
             ],
         },
         InstructionFormat {
@@ -1175,6 +1190,7 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 3, shift: 0, value: 0b101 },
                 InstructionBits { usage: InstructionBitsUsage::Rm, bit_count: 3, shift: 0, value: 0 },
                 InstructionBits { usage: InstructionBitsUsage::W, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::Far, bit_count: 0, shift: 0, value: 1 },
             ],
         },
 
@@ -1193,11 +1209,11 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             ],
         },
         InstructionFormat {
-            op: OperationType::Ret,
+            op: OperationType::Retf,
             bits: vec![InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b11001011 }],
         },
         InstructionFormat {
-            op: OperationType::Ret,
+            op: OperationType::Retf,
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 8, shift: 0, value: 0b11001010 },
                 InstructionBits { usage: InstructionBitsUsage::HasData, bit_count: 0, shift: 0, value: 1 },
@@ -1433,10 +1449,12 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
             op: OperationType::Esc,
             bits: vec![
                 InstructionBits { usage: InstructionBitsUsage::Literal, bit_count: 5, shift: 0, value: 0b11011 },
-                InstructionBits { usage: InstructionBitsUsage::Data, bit_count: 3, shift: 0, value: 0 },
+                InstructionBits { usage: InstructionBitsUsage::Data, bit_count: 3, shift: 3, value: 0 },
                 InstructionBits { usage: InstructionBitsUsage::Mod, bit_count: 2, shift: 0, value: 0 },
-                InstructionBits { usage: InstructionBitsUsage::Data, bit_count: 3, shift: 0, value: 3 },
+                InstructionBits { usage: InstructionBitsUsage::Data, bit_count: 3, shift: 0, value: 0 },
                 InstructionBits { usage: InstructionBitsUsage::Rm, bit_count: 3, shift: 0, value: 0 },
+                InstructionBits { usage: InstructionBitsUsage::D, bit_count: 0, shift: 0, value: 1 },
+                InstructionBits { usage: InstructionBitsUsage::RmRegAlwaysW, bit_count: 0, shift: 0, value: 1 },
             ],
         },
 
@@ -1458,3 +1476,85 @@ pub fn get_instruction_formats() -> Vec<InstructionFormat> {
     ]
 }
 
+/// Candidate formats for one opcode byte, in the same order as
+/// `get_instruction_formats` so the first match still wins.
+#[derive(Debug, Clone)]
+pub enum OpcodeEntry {
+    Formats(Vec<InstructionFormat>),
+    /// Group opcodes such as 0x80 or 0xF6, whose operation is picked by the
+    /// reg field of the ModRM byte that follows.
+    ByReg([Vec<InstructionFormat>; 8]),
+}
+
+static OPCODE_TABLE: LazyLock<Vec<OpcodeEntry>> = LazyLock::new(build_opcode_table);
+
+/// Formats that can decode an instruction starting with `first_byte`. The
+/// table is built on first use.
+pub fn opcode_formats(first_byte: u8) -> &'static OpcodeEntry {
+    &OPCODE_TABLE[first_byte as usize]
+}
+
+fn build_opcode_table() -> Vec<OpcodeEntry> {
+    let formats = get_instruction_formats();
+    let collect = |indices: &[usize]| indices.iter().map(|&i| formats[i].clone()).collect::<Vec<_>>();
+
+    (0..=255u8)
+        .map(|first_byte| {
+            let by_reg: [Vec<usize>; 8] = std::array::from_fn(|reg| {
+                (0..formats.len())
+                    .filter(|&i| could_match(&formats[i], first_byte, reg as u8))
+                    .collect()
+            });
+
+            if by_reg.iter().all(|indices| *indices == by_reg[0]) {
+                OpcodeEntry::Formats(collect(&by_reg[0]))
+            } else {
+                OpcodeEntry::ByReg(std::array::from_fn(|reg| collect(&by_reg[reg])))
+            }
+        })
+        .collect()
+}
+
+/// Whether `format` can match an instruction whose first byte is
+/// `first_byte` and whose second byte has `reg` in bits 3-5. Literal fields
+/// that fall outside those known bits are assumed to match.
+fn could_match(format: &InstructionFormat, first_byte: u8, reg: u8) -> bool {
+    let known = [(first_byte, 0xffu8), (reg << 3, 0b00111000)];
+    let mut byte_index = 0usize;
+    let mut bits_pending_count = 0u8;
+
+    for test_bits in &format.bits {
+        if test_bits.usage == InstructionBitsUsage::Literal && test_bits.bit_count == 0 {
+            break;
+        }
+        if test_bits.bit_count == 0 {
+            continue;
+        }
+
+        if bits_pending_count == 0 {
+            if byte_index == known.len() {
+                break;
+            }
+            bits_pending_count = 8;
+            byte_index += 1;
+        }
+
+        // try_decode rejects a field that straddles a byte boundary
+        if test_bits.bit_count > bits_pending_count {
+            return false;
+        }
+        bits_pending_count -= test_bits.bit_count;
+
+        if test_bits.usage == InstructionBitsUsage::Literal {
+            let (value, mask) = known[byte_index - 1];
+            let field_mask = !(0xffu32 << test_bits.bit_count);
+            let mask = (mask as u32 >> bits_pending_count) & field_mask;
+            let value = (value as u32 >> bits_pending_count) & mask;
+            if (test_bits.value as u32 & mask) != value {
+                return false;
+            }
+        }
+    }
+
+    true
+}
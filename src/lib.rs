@@ -3,3 +3,9 @@ pub mod memory;
 pub mod instruction_formats;
 pub mod register;
 pub mod execution_unit;
+pub mod simulator;
+pub mod port_bus;
+pub mod pic;
+pub mod pit;
+pub mod timing;
+pub mod fpu;
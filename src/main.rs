@@ -1,40 +1,96 @@
 use std::io::{self, Write};
 
 use sim86::{
-    decoder::{
-        decode_instruction, DisasmContext, Instruction, InstructionFlag, Operand,
-    }, 
-    instruction_formats::OperationType, memory::{Memory, SegmentedAccess},
-    register::{RegisterFile, RegisterAccess, RegisterIndex},
-    execution_unit::execute_instruction,
+    decoder::{decode_instruction, Instruction, InstructionFlag, Operand, Prefixes, RepPrefix},
+    fpu::{Fpu, FpuInstruction, FpuOperand},
+    instruction_formats::OperationType, memory::SegmentedAccess,
+    register::EffectiveAddressExpression,
+    simulator::Simulator,
 };
 
-pub fn is_printable(instruction: &Instruction) -> bool {
-    !matches!(instruction.op, OperationType::Lock | OperationType::Rep | OperationType::Segment)
+fn print_address(address: &EffectiveAddressExpression, prefixes: &Prefixes, output: &mut dyn Write) -> io::Result<()> {
+    // Segment prefix
+    if prefixes.segment.is_some() {
+        write!(output, "{}:", address.segment.get_name(0, 2))?;
+    }
+
+    // Address formatting
+    write!(output, "[{}", address.base.expression())?;
+    if address.displacement != 0 {
+        write!(output, "{:+}", address.displacement)?;
+    }
+    write!(output, "]")
+}
+
+fn print_fpu_instruction(instruction: &FpuInstruction, prefixes: &Prefixes, output: &mut dyn Write) -> io::Result<()> {
+    write!(output, "{} ", instruction.op.mnemonic())?;
+
+    let mut separator = "";
+    for operand in &instruction.operands {
+        match operand {
+            FpuOperand::None => continue,
+            FpuOperand::St(i) => write!(output, "{}st{}", separator, i)?,
+            FpuOperand::Memory(address, format) => {
+                write!(output, "{}", separator)?;
+                if !format.size_name().is_empty() {
+                    write!(output, "{} ", format.size_name())?;
+                }
+                print_address(address, prefixes, output)?;
+            }
+        }
+        separator = ", ";
+    }
+
+    Ok(())
 }
 
 pub fn print_instruction(instruction: &Instruction, output: &mut dyn Write) -> io::Result<()> {
     let flags = instruction.flags;
     let w = (flags & InstructionFlag::WIDE) != 0;
+    let prefixes = instruction.prefixes;
+
+    if prefixes.lock {
+        write!(output, "lock ")?;
+    }
+
+    let is_string = matches!(
+        instruction.op,
+        OperationType::Movs | OperationType::Cmps | OperationType::Scas | OperationType::Lods | OperationType::Stos
+    );
 
-    if (flags & InstructionFlag::LOCK) != 0 {
-        if instruction.op == OperationType::Xchg {
-            write!(output, "lock ")?;
+    if let Some(rep) = prefixes.rep {
+        let prefix = if rep == RepPrefix::Repne {
+            "repne"
+        } else if matches!(instruction.op, OperationType::Cmps | OperationType::Scas) {
+            "repe"
         } else {
-            write!(output, "lock ")?;
-        }
+            "rep"
+        };
+        write!(output, "{} ", prefix)?;
+    }
+
+    // Without a memory operand to carry it, an override prints as a prefix
+    let has_memory = instruction.operands.iter().any(|operand| matches!(operand, Operand::Memory(_)));
+    if let (false, Some(segment)) = (has_memory, prefixes.segment) {
+        write!(output, "{} ", segment.get_name(0, 2))?;
     }
 
-    let mut mnemonic_suffix = "";
-    if (flags & InstructionFlag::REP) != 0 {
-        write!(output, "rep ")?;
-        mnemonic_suffix = if w { "w" } else { "b" };
+    if let Some(fpu_instruction) = FpuInstruction::decode(instruction) {
+        return print_fpu_instruction(&fpu_instruction, &prefixes, output);
     }
 
+    let mnemonic_suffix = if !is_string { "" } else if w { "w" } else { "b" };
+
     write!(output, "{}{} ", instruction.op.mnemonic(), mnemonic_suffix)?;
 
+    // AAM and AAD print bare for the standard base 10, as assemblers write them
+    let operands = match (instruction.op, instruction.operands[0]) {
+        (OperationType::Aam | OperationType::Aad, Operand::Immediate(10)) => &[][..],
+        _ => &instruction.operands[..],
+    };
+
     let mut separator = "";
-    for operand in &instruction.operands {
+    for operand in operands {
         if *operand != Operand::None {
             write!(output, "{}", separator)?;
             separator = ", ";
@@ -51,20 +107,17 @@ pub fn print_instruction(instruction: &Instruction, output: &mut dyn Write) -> i
                         && !matches!(instruction.operands[0], Operand::RelativeImmediate(_))
                         && !matches!(instruction.operands[1], Operand::RelativeImmediate(_))
                     {
-                        write!(output, "{} ", if w { "word" } else { "byte" })?;
-                    }
-
-                    // Segment prefix
-                    if (flags & InstructionFlag::SEGMENT) != 0 {
-                        write!(output, "{}:", address.segment.get_name(0, 2))?;
+                        let size = if (flags & InstructionFlag::FAR) != 0 {
+                            "far"
+                        } else if w {
+                            "word"
+                        } else {
+                            "byte"
+                        };
+                        write!(output, "{} ", size)?;
                     }
 
-                    // Address formatting
-                    write!(output, "[{}", address.base.expression())?;
-                    if address.displacement != 0 {
-                        write!(output, "{:+}", address.displacement)?;
-                    }
-                    write!(output, "]")?;
+                    print_address(address, &prefixes, output)?;
                 }
                 Operand::Immediate(val) => {
                     write!(output, "{}", val)?;
@@ -72,6 +125,9 @@ pub fn print_instruction(instruction: &Instruction, output: &mut dyn Write) -> i
                 Operand::RelativeImmediate(offset) => {
                     write!(output, "${:+}", offset)?;
                 }
+                Operand::FarPointer { segment, offset } => {
+                    write!(output, "{}:{}", segment, offset)?;
+                }
             }
         }
     }
@@ -79,60 +135,90 @@ pub fn print_instruction(instruction: &Instruction, output: &mut dyn Write) -> i
     Ok(())
 }
 
-pub fn disasm_8086(memory: &Memory, disasm_byte_count: u32, disasm_start: SegmentedAccess) -> io::Result<()> {
-    let mut at = disasm_start;
-    let mut context = DisasmContext::new();
-    let mut register_file = RegisterFile::new();
-
+/// Traces execution from `disasm_start`, printing each instruction. Bytes that
+/// do not decode end the listing, or with `resync` are printed as `db` and
+/// skipped one at a time.
+pub fn disasm_8086(
+    simulator: &mut Simulator,
+    disasm_byte_count: u32,
+    disasm_start: SegmentedAccess,
+    resync: bool,
+) -> io::Result<()> {
     let starting_address = disasm_start.get_absolute_address(0);
-    register_file.update_ip(starting_address as u16);
+    simulator.registers.cs = disasm_start.segment_base;
+    simulator.registers.ip = disasm_start.segment_offset;
 
     loop {
-        at.segment_offset = register_file.get_register_value(&RegisterAccess {index: RegisterIndex::IP, offset: 0, count: 2});
-
-        let instruction = decode_instruction(&context, memory, &mut at);
+        // Fetch from wherever the previous instruction left CS:IP, and stop
+        // once control leaves the loaded image.
+        let at = SegmentedAccess {
+            segment_base: simulator.registers.cs,
+            segment_offset: simulator.registers.ip,
+        };
+        let current_address = at.get_absolute_address(0);
 
-        if instruction.op == OperationType::None {
-            eprintln!("ERROR: Unrecognized binary in instruction stream.");
+        if current_address.wrapping_sub(starting_address) >= disasm_byte_count {
             break;
         }
 
-        register_file.update_ip(instruction.size as u16);
-        let current_address = at.get_absolute_address(0);
+        // Decoding against the image alone reports a cut-off final
+        // instruction instead of reading the zeros after it
+        let image_end = (starting_address + disasm_byte_count) as usize;
+        let image = &simulator.memory.bytes[..image_end.min(simulator.memory.bytes.len())];
+        let instruction = match decode_instruction(image, &at) {
+            Ok(instruction) => instruction,
+            Err(error) if !resync => {
+                eprintln!("ERROR: {}", error);
+                break;
+            }
+            Err(error) => {
+                println!("db 0x{:02x}", error.bytes[0]);
+                simulator.registers.ip = simulator.registers.ip.wrapping_add(1);
+                continue;
+            }
+        };
+        simulator.execute(&instruction);
 
-        if current_address - starting_address >= disasm_byte_count {
+        print_instruction(&instruction, &mut io::stdout())?;
+        println!();
+
+        // HLT ends the trace unless an interrupt comes along to resume it
+        let stopped = simulator.halted && !simulator.wait_for_interrupt();
+        if let Some(vector) = simulator.unhandled_interrupt {
+            eprintln!("ERROR: Interrupt {:02X}h has no handler.", vector);
             break;
         }
-
-        execute_instruction(&instruction, memory, &mut register_file)?;
-        context.update(&instruction);
-
-        if is_printable(&instruction) {
-            print_instruction(&instruction, &mut io::stdout())?;
-            println!();
+        if stopped {
+            break;
         }
     }
 
-    register_file.print_state();
+    simulator.registers.print_state();
+    if let Some(fpu) = simulator.fpu.as_ref().filter(|fpu| fpu.st(0).is_some()) {
+        fpu.print_state();
+    }
 
     Ok(())
 }
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <filename>", args[0]);
+    let resync = args[1..].iter().any(|arg| arg == "--resync");
+    let filenames: Vec<&String> = args[1..].iter().filter(|arg| *arg != "--resync").collect();
+    if filenames.len() != 1 {
+        eprintln!("Usage: {} [--resync] <filename>", args[0]);
         return Ok(());
     }
 
-    let filename = &args[1];
-    let mut memory = Memory::new();
+    let filename = filenames[0];
+    let mut simulator = Simulator::new();
+    simulator.fpu = Some(Fpu::new());
 
-    match memory.load_from_file(filename, 0) {
+    match simulator.memory.load_from_file(filename, 0) {
         Ok(bytes_read) => {
             println!("; {} disassembly:", filename);
             println!("bits 16");
-            disasm_8086(&memory, bytes_read, SegmentedAccess::default())?;
+            disasm_8086(&mut simulator, bytes_read, SegmentedAccess::default(), resync)?;
         }
         Err(e) => {
             eprintln!("ERROR: Unable to open {}: {}", filename, e);
@@ -141,4 +227,3 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
-
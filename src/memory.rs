@@ -5,13 +5,21 @@ const MEMORY_SIZE: usize = 1024 * 1024;
 const MEMORY_ACCESS_MASK: u32 = 0xfffff;
 
 pub struct Memory {
-    pub bytes: [u8; MEMORY_SIZE],
+    pub bytes: Box<[u8; MEMORY_SIZE]>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Self {
+        // Built on the heap: a 1 MiB array overflows a spawned thread's stack
+        let bytes = vec![0; MEMORY_SIZE].into_boxed_slice();
         Self {
-            bytes: [0; MEMORY_SIZE],
+            bytes: bytes.try_into().expect("allocated MEMORY_SIZE bytes"),
         }
     }
 
@@ -20,6 +28,30 @@ impl Memory {
         self.bytes[absolute_address as usize]
     }
 
+    pub fn write(&mut self, absolute_address: u32, value: u8) {
+        assert!((absolute_address as usize) < self.bytes.len());
+        self.bytes[absolute_address as usize] = value;
+    }
+
+    /// Reads a byte or a little-endian word. The second byte of a word wraps
+    /// within the segment, as it does on the 8086.
+    pub fn read_value(&self, at: &SegmentedAccess, wide: bool) -> u16 {
+        let low = self.read(at.get_absolute_address(0)) as u16;
+        if wide {
+            let high = self.read(at.get_absolute_address(1)) as u16;
+            (high << 8) | low
+        } else {
+            low
+        }
+    }
+
+    pub fn write_value(&mut self, at: &SegmentedAccess, wide: bool, value: u16) {
+        self.write(at.get_absolute_address(0), (value & 0xFF) as u8);
+        if wide {
+            self.write(at.get_absolute_address(1), (value >> 8) as u8);
+        }
+    }
+
     pub fn load_from_file(&mut self, filename: &str, at_offset: u32) -> io::Result<u32> {
         if (at_offset as usize) >= self.bytes.len() {
             return Ok(0);
@@ -39,26 +71,35 @@ impl Memory {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct SegmentedAccess {
-    pub segment_base: u16,
-    pub segment_offset: u16,
+/// Anything instructions can be decoded from, addressed by the 20-bit
+/// physical addresses that `SegmentedAccess` produces.
+pub trait ByteSource {
+    /// The byte at `absolute_address`, or None past the end of the source.
+    fn read_byte(&self, absolute_address: u32) -> Option<u8>;
 }
 
-impl Default for SegmentedAccess {
-    fn default() -> Self {
-        Self {
-            segment_base: 0,
-            segment_offset: 0,
-        }
+impl ByteSource for Memory {
+    fn read_byte(&self, absolute_address: u32) -> Option<u8> {
+        Some(self.read(absolute_address))
+    }
+}
+
+/// A byte slice reads as an image loaded at physical address 0.
+impl ByteSource for [u8] {
+    fn read_byte(&self, absolute_address: u32) -> Option<u8> {
+        self.get(absolute_address as usize).copied()
     }
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SegmentedAccess {
+    pub segment_base: u16,
+    pub segment_offset: u16,
+}
+
 impl SegmentedAccess {
     pub fn get_absolute_address(&self, additional_offset: u16) -> u32 {
-        let result = (((self.segment_base as u32) << 4) + 
-                     (self.segment_offset + additional_offset) as u32) & MEMORY_ACCESS_MASK;
-        result
+        (((self.segment_base as u32) << 4) +
+            self.segment_offset.wrapping_add(additional_offset) as u32) & MEMORY_ACCESS_MASK
     }
 }
-
@@ -0,0 +1,369 @@
+use crate::port_bus::PortDevice;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InitStep {
+    Ready,
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
+/// Emulated 8259A programmable interrupt controller, wired as the single
+/// master PIC of a PC on ports 0x20 and 0x21.
+///
+/// Out of reset it behaves as the PC BIOS leaves it: edge triggered, vectors
+/// at 08h, no lines masked. Programs can reprogram it with ICW1-ICW4 and
+/// control it with OCW1-OCW3.
+#[derive(Debug, Clone)]
+pub struct Pic {
+    irr: u8,
+    isr: u8,
+    imr: u8,
+    lines: u8,
+    vector_base: u8,
+    level_triggered: bool,
+    single: bool,
+    needs_icw4: bool,
+    auto_eoi: bool,
+    rotate_in_auto_eoi: bool,
+    special_mask: bool,
+    read_isr: bool,
+    poll: bool,
+    lowest_priority: u8,
+    init_step: InitStep,
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pic {
+    pub fn new() -> Self {
+        Self {
+            irr: 0,
+            isr: 0,
+            imr: 0,
+            lines: 0,
+            vector_base: 0x08,
+            level_triggered: false,
+            single: true,
+            needs_icw4: true,
+            auto_eoi: false,
+            rotate_in_auto_eoi: false,
+            special_mask: false,
+            read_isr: false,
+            poll: false,
+            lowest_priority: 7,
+            init_step: InitStep::Ready,
+        }
+    }
+
+    /// Drives IRQ `line` high. In edge-triggered mode only a low-to-high
+    /// transition latches a request.
+    pub fn raise_irq(&mut self, line: u8) {
+        let bit = 1 << (line & 7);
+        if self.level_triggered || (self.lines & bit) == 0 {
+            self.irr |= bit;
+        }
+        self.lines |= bit;
+    }
+
+    /// Drives IRQ `line` low. In level-triggered mode this withdraws the request.
+    pub fn lower_irq(&mut self, line: u8) {
+        let bit = 1 << (line & 7);
+        self.lines &= !bit;
+        if self.level_triggered {
+            self.irr &= !bit;
+        }
+    }
+
+    /// Sets the level of IRQ `line` without latching a request, for a device
+    /// whose output is already high when it is wired up.
+    pub fn seed_line(&mut self, line: u8, high: bool) {
+        let bit = 1 << (line & 7);
+        if high {
+            self.lines |= bit;
+        } else {
+            self.lines &= !bit;
+        }
+    }
+
+    pub fn irr(&self) -> u8 {
+        self.irr
+    }
+
+    pub fn isr(&self) -> u8 {
+        self.isr
+    }
+
+    pub fn imr(&self) -> u8 {
+        self.imr
+    }
+
+    /// Position of `level` in the current priority order, 0 being the highest.
+    fn priority(&self, level: u8) -> u8 {
+        level.wrapping_sub(self.lowest_priority).wrapping_sub(1) & 7
+    }
+
+    fn highest_priority(&self, bits: u8) -> Option<u8> {
+        (0..8)
+            .map(|i| (self.lowest_priority + 1 + i) & 7)
+            .find(|level| (bits & (1 << level)) != 0)
+    }
+
+    /// Returns the IRQ line that would be delivered next. In the fully nested
+    /// mode a request must outrank every level already in service.
+    pub fn pending(&self) -> Option<u8> {
+        let level = self.highest_priority(self.irr & !self.imr)?;
+        let in_service = if self.special_mask { self.isr & !self.imr } else { self.isr };
+
+        match self.highest_priority(in_service) {
+            Some(active) if self.priority(active) <= self.priority(level) => None,
+            _ => Some(level),
+        }
+    }
+
+    fn acknowledge_level(&mut self) -> Option<u8> {
+        let level = self.pending()?;
+        let bit = 1 << level;
+
+        if !self.level_triggered || (self.lines & bit) == 0 {
+            self.irr &= !bit;
+        }
+
+        if !self.auto_eoi {
+            self.isr |= bit;
+        } else if self.rotate_in_auto_eoi {
+            self.lowest_priority = level;
+        }
+
+        Some(level)
+    }
+
+    /// Runs the INTA cycle for the highest pending request and returns the
+    /// interrupt type the CPU should dispatch.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        self.acknowledge_level().map(|level| self.vector_base | level)
+    }
+
+    fn end_of_interrupt(&mut self, level: Option<u8>, rotate: bool) {
+        if let Some(level) = level.or_else(|| self.highest_priority(self.isr)) {
+            self.isr &= !(1 << level);
+            if rotate {
+                self.lowest_priority = level;
+            }
+        }
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if (value & 0x10) != 0 {
+            // ICW1 restarts initialization
+            self.level_triggered = (value & 0x08) != 0;
+            self.single = (value & 0x02) != 0;
+            self.needs_icw4 = (value & 0x01) != 0;
+            self.irr = 0;
+            self.isr = 0;
+            self.imr = 0;
+            self.auto_eoi = false;
+            self.special_mask = false;
+            self.read_isr = false;
+            self.poll = false;
+            self.lowest_priority = 7;
+            self.init_step = InitStep::Icw2;
+        } else if (value & 0x08) == 0 {
+            // OCW2
+            let level = value & 0x07;
+            match value >> 5 {
+                0b001 => self.end_of_interrupt(None, false),
+                0b011 => self.end_of_interrupt(Some(level), false),
+                0b101 => self.end_of_interrupt(None, true),
+                0b111 => self.end_of_interrupt(Some(level), true),
+                0b100 => self.rotate_in_auto_eoi = true,
+                0b000 => self.rotate_in_auto_eoi = false,
+                0b110 => self.lowest_priority = level,
+                _ => {}
+            }
+        } else {
+            // OCW3
+            if (value & 0x02) != 0 {
+                self.read_isr = (value & 0x01) != 0;
+            }
+            if (value & 0x40) != 0 {
+                self.special_mask = (value & 0x20) != 0;
+            }
+            self.poll = (value & 0x04) != 0;
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.init_step = match self.init_step {
+            InitStep::Ready => {
+                // OCW1
+                self.imr = value;
+                InitStep::Ready
+            }
+            InitStep::Icw2 => {
+                self.vector_base = value & 0xF8;
+                if !self.single {
+                    InitStep::Icw3
+                } else if self.needs_icw4 {
+                    InitStep::Icw4
+                } else {
+                    InitStep::Ready
+                }
+            }
+            InitStep::Icw3 => {
+                // Cascading is not emulated, so the slave map is ignored
+                if self.needs_icw4 { InitStep::Icw4 } else { InitStep::Ready }
+            }
+            InitStep::Icw4 => {
+                self.auto_eoi = (value & 0x02) != 0;
+                InitStep::Ready
+            }
+        };
+    }
+}
+
+impl PortDevice for Pic {
+    fn read_byte(&mut self, port: u16) -> u8 {
+        if self.poll {
+            // A poll read acknowledges like INTA but returns the level instead
+            self.poll = false;
+            return match self.acknowledge_level() {
+                Some(level) => 0x80 | level,
+                None => 0,
+            };
+        }
+
+        if (port & 1) == 0 {
+            if self.read_isr { self.isr } else { self.irr }
+        } else {
+            self.imr
+        }
+    }
+
+    fn write_byte(&mut self, port: u16, value: u8) {
+        if (port & 1) == 0 {
+            self.write_command(value);
+        } else {
+            self.write_data(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMAND: u16 = 0x20;
+    const DATA: u16 = 0x21;
+
+    fn initialized(icw1: u8, icw2: u8, icw4: u8) -> Pic {
+        let mut pic = Pic::new();
+        pic.write_byte(COMMAND, icw1);
+        pic.write_byte(DATA, icw2);
+        pic.write_byte(DATA, icw4);
+        pic
+    }
+
+    #[test]
+    fn edge_triggered_lines_latch_once_per_rising_edge() {
+        let mut pic = Pic::new();
+        pic.raise_irq(3);
+        assert_eq!(pic.acknowledge(), Some(0x0B));
+        pic.write_byte(COMMAND, 0x20);
+
+        // Still high: no new edge
+        pic.raise_irq(3);
+        assert_eq!(pic.acknowledge(), None);
+
+        pic.lower_irq(3);
+        pic.raise_irq(3);
+        assert_eq!(pic.acknowledge(), Some(0x0B));
+    }
+
+    #[test]
+    fn level_triggered_request_follows_the_line() {
+        let mut pic = initialized(0x1B, 0x08, 0x01);
+        pic.raise_irq(2);
+        assert_eq!(pic.irr(), 0x04);
+        pic.lower_irq(2);
+        assert_eq!(pic.irr(), 0);
+        assert_eq!(pic.acknowledge(), None);
+    }
+
+    #[test]
+    fn in_service_level_blocks_equal_and_lower_priorities() {
+        let mut pic = Pic::new();
+        pic.raise_irq(1);
+        assert_eq!(pic.acknowledge(), Some(0x09));
+        assert_eq!(pic.isr(), 0x02);
+
+        pic.raise_irq(4);
+        assert_eq!(pic.pending(), None);
+        // IRQ0 outranks the level in service and nests inside it
+        pic.raise_irq(0);
+        assert_eq!(pic.acknowledge(), Some(0x08));
+
+        // Non-specific EOIs retire the highest level first
+        pic.write_byte(COMMAND, 0x20);
+        assert_eq!(pic.isr(), 0x02);
+        pic.write_byte(COMMAND, 0x20);
+        assert_eq!(pic.acknowledge(), Some(0x0C));
+    }
+
+    #[test]
+    fn masked_requests_wait_for_unmask() {
+        let mut pic = Pic::new();
+        pic.write_byte(DATA, 0x01);
+        pic.raise_irq(0);
+        assert_eq!(pic.acknowledge(), None);
+        assert_eq!(pic.read_byte(DATA), 0x01);
+
+        pic.write_byte(DATA, 0x00);
+        assert_eq!(pic.acknowledge(), Some(0x08));
+    }
+
+    #[test]
+    fn initialization_sets_vector_base_and_auto_eoi() {
+        let mut pic = initialized(0x13, 0x70, 0x03);
+        pic.raise_irq(5);
+        assert_eq!(pic.acknowledge(), Some(0x75));
+        assert_eq!(pic.isr(), 0);
+    }
+
+    #[test]
+    fn specific_rotation_changes_priority_order() {
+        let mut pic = Pic::new();
+        // Set priority: IRQ4 lowest, so IRQ5 is served first
+        pic.write_byte(COMMAND, 0xC4);
+        pic.raise_irq(0);
+        pic.raise_irq(5);
+        assert_eq!(pic.acknowledge(), Some(0x0D));
+    }
+
+    #[test]
+    fn poll_acknowledges_and_returns_the_level() {
+        let mut pic = Pic::new();
+        pic.raise_irq(6);
+        pic.write_byte(COMMAND, 0x0C);
+        assert_eq!(pic.read_byte(COMMAND), 0x86);
+        assert_eq!(pic.isr(), 0x40);
+
+        pic.write_byte(COMMAND, 0x0C);
+        assert_eq!(pic.read_byte(COMMAND), 0);
+    }
+
+    #[test]
+    fn ocw3_selects_irr_or_isr_reads() {
+        let mut pic = Pic::new();
+        pic.raise_irq(1);
+        pic.raise_irq(2);
+        pic.acknowledge();
+        assert_eq!(pic.read_byte(COMMAND), 0x04);
+        pic.write_byte(COMMAND, 0x0B);
+        assert_eq!(pic.read_byte(COMMAND), 0x02);
+    }
+}
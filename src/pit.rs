@@ -0,0 +1,399 @@
+use crate::port_bus::PortDevice;
+
+/// Input clock of the 8253 in a PC, a quarter of the 4.77 MHz CPU clock.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+/// CPU clocks per PIT input clock.
+pub const CPU_CYCLES_PER_PIT_TICK: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Lsb,
+    Msb,
+    LsbMsb,
+}
+
+#[derive(Debug, Clone)]
+struct Channel {
+    mode: u8,
+    access: Access,
+    bcd: bool,
+    reload: u16,
+    counter: u16,
+    latch: Option<u16>,
+    read_msb_next: bool,
+    write_msb_next: bool,
+    output: bool,
+    gate: bool,
+    counting: bool,
+    rising_edge: bool,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            mode: 0,
+            access: Access::LsbMsb,
+            bcd: false,
+            reload: 0,
+            counter: 0,
+            latch: None,
+            read_msb_next: false,
+            write_msb_next: false,
+            output: false,
+            gate: true,
+            counting: false,
+            rising_edge: false,
+        }
+    }
+
+    fn set_output(&mut self, output: bool) {
+        if output && !self.output {
+            self.rising_edge = true;
+        }
+        self.output = output;
+    }
+
+    fn set_control(&mut self, mode: u8, access: Access, bcd: bool) {
+        // Modes 6 and 7 are aliases of 2 and 3
+        self.mode = if mode >= 6 { mode - 4 } else { mode };
+        self.access = access;
+        self.bcd = bcd;
+        self.latch = None;
+        self.read_msb_next = false;
+        self.write_msb_next = false;
+        self.counting = false;
+        self.output = self.mode != 0;
+    }
+
+    fn load(&mut self) {
+        self.counter = self.reload;
+        self.counting = true;
+        match self.mode {
+            0 => self.output = false,
+            1 | 5 => self.counting = false, // wait for a gate trigger
+            _ => self.output = true,
+        }
+    }
+
+    fn write_count(&mut self, value: u8) {
+        let complete = match self.access {
+            Access::Lsb => {
+                self.reload = value as u16;
+                true
+            }
+            Access::Msb => {
+                self.reload = (value as u16) << 8;
+                true
+            }
+            Access::LsbMsb if !self.write_msb_next => {
+                self.reload = (self.reload & 0xFF00) | value as u16;
+                self.write_msb_next = true;
+                // Mode 0 stops counting while a new count is half written
+                if self.mode == 0 {
+                    self.counting = false;
+                    self.output = false;
+                }
+                false
+            }
+            Access::LsbMsb => {
+                self.reload = (self.reload & 0x00FF) | ((value as u16) << 8);
+                self.write_msb_next = false;
+                true
+            }
+        };
+
+        // Periodic modes pick up a new count at the end of the current period
+        if complete && !(self.counting && matches!(self.mode, 2 | 3)) {
+            self.load();
+        }
+    }
+
+    fn read_count(&mut self) -> u8 {
+        let value = self.latch.unwrap_or(self.counter);
+        let (byte, done) = match self.access {
+            Access::Lsb => (value as u8, true),
+            Access::Msb => ((value >> 8) as u8, true),
+            Access::LsbMsb if !self.read_msb_next => {
+                self.read_msb_next = true;
+                (value as u8, false)
+            }
+            Access::LsbMsb => {
+                self.read_msb_next = false;
+                ((value >> 8) as u8, true)
+            }
+        };
+
+        if done {
+            self.latch = None;
+        }
+        byte
+    }
+
+    fn set_gate(&mut self, gate: bool) {
+        let rising = gate && !self.gate;
+        self.gate = gate;
+
+        match self.mode {
+            1 | 5 if rising => {
+                self.counter = self.reload;
+                self.counting = true;
+                if self.mode == 1 {
+                    self.output = false;
+                }
+            }
+            2 | 3 if rising => self.counter = self.reload,
+            2 | 3 if !gate => self.set_output(true),
+            _ => {}
+        }
+    }
+
+    /// Decrement step of the counting element. BCD mode wraps at 9999.
+    fn decrement(&self, value: u16, amount: u16) -> u16 {
+        if !self.bcd {
+            return value.wrapping_sub(amount);
+        }
+
+        let digits = |v: u16| (v >> 12) * 1000 + ((v >> 8) & 0xF) * 100 + ((v >> 4) & 0xF) * 10 + (v & 0xF);
+        let binary = (digits(value) + 10000 - amount) % 10000;
+        ((binary / 1000) << 12) | (((binary / 100) % 10) << 8) | (((binary / 10) % 10) << 4) | (binary % 10)
+    }
+
+    fn clock(&mut self) {
+        if !self.counting || (!self.gate && matches!(self.mode, 0 | 2 | 3 | 4)) {
+            return;
+        }
+
+        match self.mode {
+            0 | 1 => {
+                self.counter = self.decrement(self.counter, 1);
+                if self.counter == 0 {
+                    self.set_output(true);
+                }
+            }
+            2 => {
+                self.counter = self.decrement(self.counter, 1);
+                if self.counter == 1 {
+                    self.output = false;
+                } else if self.counter == 0 {
+                    self.counter = self.reload;
+                    self.set_output(true);
+                }
+            }
+            3 => {
+                // Odd counts spend one extra clock in the high half
+                let step = if (self.counter & 1) != 0 {
+                    if self.output { 1 } else { 3 }
+                } else {
+                    2
+                };
+                let remaining = if self.counter == 0 { 0x10000 } else { self.counter as u32 };
+                if remaining <= step {
+                    self.counter = self.reload;
+                    let output = !self.output;
+                    self.set_output(output);
+                } else {
+                    self.counter = self.decrement(self.counter, step as u16);
+                }
+            }
+            _ => {
+                // Modes 4 and 5 strobe the output low for one clock at terminal count
+                if !self.output {
+                    self.set_output(true);
+                    self.counting = false;
+                    return;
+                }
+                self.counter = self.decrement(self.counter, 1);
+                if self.counter == 0 {
+                    self.output = false;
+                }
+            }
+        }
+    }
+}
+
+/// Emulated 8253 programmable interval timer on ports 0x40-0x43.
+///
+/// Channel 0 drives IRQ0 and starts out the way the PC BIOS programs it,
+/// mode 3 with a count of 65536 for the 18.2 Hz tick. Channel 2 feeds the
+/// speaker: its gate and output are exposed for that logic.
+#[derive(Debug, Clone)]
+pub struct Pit {
+    channels: [Channel; 3],
+}
+
+impl Default for Pit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pit {
+    pub fn new() -> Self {
+        let mut channels = [Channel::new(), Channel::new(), Channel::new()];
+        channels[0].set_control(3, Access::LsbMsb, false);
+        channels[0].load();
+        Self { channels }
+    }
+
+    /// Advances every channel by `ticks` input clocks.
+    pub fn advance(&mut self, ticks: u64) {
+        for channel in &mut self.channels {
+            for _ in 0..ticks {
+                channel.clock();
+            }
+        }
+    }
+
+    pub fn output(&self, channel: usize) -> bool {
+        self.channels[channel].output
+    }
+
+    /// Reports whether the output of `channel` went high since the last call.
+    pub fn take_rising_edge(&mut self, channel: usize) -> bool {
+        std::mem::take(&mut self.channels[channel].rising_edge)
+    }
+
+    pub fn gate(&self, channel: usize) -> bool {
+        self.channels[channel].gate
+    }
+
+    /// Drives the gate input of `channel`. On a PC only channel 2 has a gate
+    /// under software control, through bit 0 of port 0x61.
+    pub fn set_gate(&mut self, channel: usize, gate: bool) {
+        self.channels[channel].set_gate(gate);
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let select = (value >> 6) as usize;
+        if select == 3 {
+            // Read-back is an 8254 extension
+            return;
+        }
+
+        let channel = &mut self.channels[select];
+        let access = match (value >> 4) & 0x3 {
+            0 => {
+                // Counter latch command
+                if channel.latch.is_none() {
+                    channel.latch = Some(channel.counter);
+                }
+                return;
+            }
+            1 => Access::Lsb,
+            2 => Access::Msb,
+            _ => Access::LsbMsb,
+        };
+        channel.set_control((value >> 1) & 0x7, access, (value & 1) != 0);
+    }
+}
+
+impl PortDevice for Pit {
+    fn read_byte(&mut self, port: u16) -> u8 {
+        match port & 3 {
+            3 => 0xFF,
+            channel => self.channels[channel as usize].read_count(),
+        }
+    }
+
+    fn write_byte(&mut self, port: u16, value: u8) {
+        match port & 3 {
+            3 => self.write_control(value),
+            channel => self.channels[channel as usize].write_count(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTROL: u16 = 0x43;
+
+    fn program(pit: &mut Pit, control: u8, count: u16) {
+        pit.write_byte(CONTROL, control);
+        let port = 0x40 + (control >> 6) as u16;
+        pit.write_byte(port, count as u8);
+        pit.write_byte(port, (count >> 8) as u8);
+    }
+
+    fn read_latched(pit: &mut Pit, channel: u16) -> u16 {
+        pit.write_byte(CONTROL, (channel as u8) << 6);
+        let low = pit.read_byte(0x40 + channel) as u16;
+        let high = pit.read_byte(0x40 + channel) as u16;
+        (high << 8) | low
+    }
+
+    #[test]
+    fn channel_0_defaults_to_the_bios_square_wave() {
+        let mut pit = Pit::new();
+        assert!(pit.output(0));
+        pit.advance(0x8000);
+        assert!(!pit.output(0));
+        assert!(!pit.take_rising_edge(0));
+        pit.advance(0x8000);
+        assert!(pit.output(0));
+        assert!(pit.take_rising_edge(0));
+        assert!(!pit.take_rising_edge(0));
+    }
+
+    #[test]
+    fn mode_2_pulses_low_for_one_clock_per_period() {
+        let mut pit = Pit::new();
+        program(&mut pit, 0x34, 4);
+        pit.advance(2);
+        assert!(pit.output(0));
+        pit.advance(1);
+        assert!(!pit.output(0));
+        pit.advance(1);
+        assert!(pit.output(0));
+        assert!(pit.take_rising_edge(0));
+        assert_eq!(read_latched(&mut pit, 0), 4);
+    }
+
+    #[test]
+    fn mode_0_goes_high_at_terminal_count_and_stays_high() {
+        let mut pit = Pit::new();
+        program(&mut pit, 0x30, 5);
+        assert!(!pit.output(0));
+        pit.advance(4);
+        assert!(!pit.output(0));
+        pit.advance(1);
+        assert!(pit.output(0));
+        pit.advance(100);
+        assert!(pit.output(0));
+    }
+
+    #[test]
+    fn latch_holds_the_count_until_read() {
+        let mut pit = Pit::new();
+        program(&mut pit, 0x34, 1000);
+        pit.advance(10);
+        pit.write_byte(CONTROL, 0x00);
+        pit.advance(10);
+        assert_eq!(pit.read_byte(0x40), (990 & 0xFF) as u8);
+        assert_eq!(pit.read_byte(0x40), (990 >> 8) as u8);
+        assert_eq!(read_latched(&mut pit, 0), 980);
+    }
+
+    #[test]
+    fn bcd_counts_in_decimal() {
+        let mut pit = Pit::new();
+        program(&mut pit, 0x31, 0x0010);
+        pit.advance(1);
+        assert_eq!(read_latched(&mut pit, 0), 0x0009);
+    }
+
+    #[test]
+    fn mode_1_one_shot_is_triggered_by_the_gate() {
+        let mut pit = Pit::new();
+        program(&mut pit, 0xB2, 3);
+        pit.advance(10);
+        assert!(pit.output(2));
+
+        pit.set_gate(2, false);
+        pit.set_gate(2, true);
+        assert!(!pit.output(2));
+        pit.advance(3);
+        assert!(pit.output(2));
+    }
+}
@@ -0,0 +1,191 @@
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+/// A peripheral that answers IN and OUT on the ports it is mapped to.
+pub trait PortDevice {
+    fn read_byte(&mut self, port: u16) -> u8;
+    fn write_byte(&mut self, port: u16, value: u8);
+
+    /// Word accesses default to two byte accesses on consecutive ports, low
+    /// byte first, the way the 8088 splits them on its 8-bit bus.
+    fn read_word(&mut self, port: u16) -> u16 {
+        let low = self.read_byte(port) as u16;
+        let high = self.read_byte(port.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
+    fn write_word(&mut self, port: u16, value: u16) {
+        self.write_byte(port, (value & 0xFF) as u8);
+        self.write_byte(port.wrapping_add(1), (value >> 8) as u8);
+    }
+}
+
+/// A shared device stays reachable from the host, e.g. to raise IRQ lines,
+/// while the bus holds its own handle to it.
+impl<T: PortDevice> PortDevice for Rc<RefCell<T>> {
+    fn read_byte(&mut self, port: u16) -> u8 {
+        self.borrow_mut().read_byte(port)
+    }
+
+    fn write_byte(&mut self, port: u16, value: u8) {
+        self.borrow_mut().write_byte(port, value)
+    }
+
+    fn read_word(&mut self, port: u16) -> u16 {
+        self.borrow_mut().read_word(port)
+    }
+
+    fn write_word(&mut self, port: u16, value: u16) {
+        self.borrow_mut().write_word(port, value)
+    }
+}
+
+struct PortMapping {
+    ports: RangeInclusive<u16>,
+    device: usize,
+}
+
+/// Routes IN and OUT to devices by port number. Word accesses go to the
+/// device that owns the first port.
+pub struct PortBus {
+    devices: Vec<Box<dyn PortDevice>>,
+    mappings: Vec<PortMapping>,
+    /// Byte returned for reads from ports no device claims.
+    pub unmapped_value: u8,
+    /// Report accesses to unmapped ports on stderr.
+    pub log_unmapped: bool,
+}
+
+impl Default for PortBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortBus {
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+            mappings: Vec::new(),
+            unmapped_value: 0xFF,
+            log_unmapped: false,
+        }
+    }
+
+    /// Maps `device` to `ports`. Later mappings take precedence over earlier
+    /// ones where they overlap.
+    pub fn map<D: PortDevice + 'static>(&mut self, ports: RangeInclusive<u16>, device: D) {
+        self.devices.push(Box::new(device));
+        self.mappings.push(PortMapping {
+            ports,
+            device: self.devices.len() - 1,
+        });
+    }
+
+    fn device_for(&mut self, port: u16) -> Option<&mut Box<dyn PortDevice>> {
+        let mapping = self.mappings.iter().rev().find(|mapping| mapping.ports.contains(&port))?;
+        self.devices.get_mut(mapping.device)
+    }
+
+    pub fn read(&mut self, port: u16, wide: bool) -> u16 {
+        match self.device_for(port) {
+            Some(device) if wide => device.read_word(port),
+            Some(device) => device.read_byte(port) as u16,
+            None => {
+                if self.log_unmapped {
+                    eprintln!("WARNING: IN from unmapped port {:#06x}", port);
+                }
+                let value = self.unmapped_value as u16;
+                if wide { (value << 8) | value } else { value }
+            }
+        }
+    }
+
+    pub fn write(&mut self, port: u16, wide: bool, value: u16) {
+        match self.device_for(port) {
+            Some(device) if wide => device.write_word(port, value),
+            Some(device) => device.write_byte(port, (value & 0xFF) as u8),
+            None => {
+                if self.log_unmapped {
+                    eprintln!("WARNING: OUT {:#06x} to unmapped port {:#06x}", value, port);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    /// Reads back the low byte of the port number plus `tag`, and records
+    /// every write.
+    #[derive(Default)]
+    struct Recorder {
+        tag: u8,
+        writes: Vec<(u16, u8)>,
+    }
+
+    impl PortDevice for Recorder {
+        fn read_byte(&mut self, port: u16) -> u8 {
+            (port as u8).wrapping_add(self.tag)
+        }
+
+        fn write_byte(&mut self, port: u16, value: u8) {
+            self.writes.push((port, value));
+        }
+    }
+
+    #[test]
+    fn unmapped_ports_read_as_the_configured_value() {
+        let mut bus = PortBus::new();
+        assert_eq!(bus.read(0x60, false), 0xFF);
+        assert_eq!(bus.read(0x60, true), 0xFFFF);
+
+        bus.unmapped_value = 0x00;
+        assert_eq!(bus.read(0x60, true), 0x0000);
+        // Writes to unmapped ports are dropped
+        bus.write(0x60, true, 0x1234);
+        assert_eq!(bus.read(0x60, false), 0x00);
+    }
+
+    #[test]
+    fn accesses_go_to_the_latest_mapping_that_covers_the_port() {
+        let low = Rc::new(RefCell::new(Recorder { tag: 0x10, ..Default::default() }));
+        let high = Rc::new(RefCell::new(Recorder { tag: 0x80, ..Default::default() }));
+        let mut bus = PortBus::new();
+        bus.map(0x40..=0x4F, low.clone());
+        bus.map(0x48..=0x48, high.clone());
+
+        assert_eq!(bus.read(0x41, false), 0x51);
+        assert_eq!(bus.read(0x48, false), 0xC8);
+        assert_eq!(bus.read(0x50, false), 0xFF);
+        // Words split into two byte accesses on the device owning the first port
+        assert_eq!(bus.read(0x47, true), 0x5857);
+
+        bus.write(0x48, false, 0x1234);
+        bus.write(0x46, true, 0xBEEF);
+        assert_eq!(high.borrow().writes, [(0x48, 0x34)]);
+        assert_eq!(low.borrow().writes, [(0x46, 0xEF), (0x47, 0xBE)]);
+    }
+
+    #[test]
+    fn in_and_out_use_immediate_and_dx_ports() {
+        // in al, 41h; mov dx, 4Ah; in ax, dx; out dx, al; out 60h, al
+        let code = [0xE4, 0x41, 0xBA, 0x4A, 0x00, 0xED, 0xEE, 0xE6, 0x60];
+        let mut simulator = Simulator::with_code(&code);
+        let device = Rc::new(RefCell::new(Recorder::default()));
+        simulator.ports.map(0x41..=0x4F, device.clone());
+
+        simulator.step().unwrap();
+        assert_eq!(simulator.registers.ax, 0x0041);
+        simulator.step().unwrap();
+        simulator.step().unwrap();
+        assert_eq!(simulator.registers.ax, 0x4B4A);
+        simulator.step().unwrap();
+        simulator.step().unwrap();
+        assert_eq!(device.borrow().writes, [(0x4A, 0x4A)]);
+    }
+}
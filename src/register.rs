@@ -1,3 +1,5 @@
+use crate::instruction_formats::OperationType;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum RegisterIndex {
@@ -86,6 +88,15 @@ impl EffectiveAddressBase {
             EffectiveAddressBase::Bx => "bx",
         }
     }
+
+    /// Segment used when no override prefix is present. Addressing through
+    /// BP is meant for stack frames, so it defaults to SS.
+    pub fn default_segment(&self) -> RegisterIndex {
+        match self {
+            EffectiveAddressBase::BpSi | EffectiveAddressBase::BpDi | EffectiveAddressBase::Bp => RegisterIndex::SS,
+            _ => RegisterIndex::DS,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -109,6 +120,9 @@ pub enum Flag {
     Auxiliary = 4,  // AF - Auxiliary Carry Flag
     Zero      = 6,  // ZF - Zero Flag
     Sign      = 7,  // SF - Sign Flag
+    Trap      = 8,  // TF - Trap Flag
+    Interrupt = 9,  // IF - Interrupt Enable Flag
+    Direction = 10, // DF - Direction Flag
     Overflow  = 11, // OF - Overflow Flag
 }
 
@@ -118,6 +132,13 @@ impl Flag {
     }
 }
 
+/// FLAGS bits that always read as one on the 8086 (bit 1 and bits 12-15).
+pub const FLAGS_RESERVED_SET: u16 = 0xF002;
+/// FLAGS bits that POPF and IRET can change.
+pub const FLAGS_WRITABLE: u16 = 0x0FD5;
+/// FLAGS bits that SAHF can change: SF, ZF, AF, PF and CF.
+pub const FLAGS_SAHF_WRITABLE: u16 = 0x00D5;
+
 #[derive(Debug, Clone, Copy)]
 pub struct RegisterFile {
     pub ax: u16,
@@ -136,6 +157,12 @@ pub struct RegisterFile {
     pub ip: u16,
 }
 
+impl Default for RegisterFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RegisterFile {
     pub fn new() -> Self {
         RegisterFile {
@@ -151,7 +178,7 @@ impl RegisterFile {
             ds: 0,
             es: 0,
             ss: 0,
-            flags: 0,
+            flags: FLAGS_RESERVED_SET,
             ip: 0,
         }
     }
@@ -174,12 +201,15 @@ impl RegisterFile {
 
         println!("\nFlags:");
         println!(
-            "CF={} PF={} AF={} ZF={} SF={} OF={}",
+            "CF={} PF={} AF={} ZF={} SF={} TF={} IF={} DF={} OF={}",
             self.get_flag(Flag::Carry) as u8,
             self.get_flag(Flag::Parity) as u8,
             self.get_flag(Flag::Auxiliary) as u8,
             self.get_flag(Flag::Zero) as u8,
             self.get_flag(Flag::Sign) as u8,
+            self.get_flag(Flag::Trap) as u8,
+            self.get_flag(Flag::Interrupt) as u8,
+            self.get_flag(Flag::Direction) as u8,
             self.get_flag(Flag::Overflow) as u8
         );
     }
@@ -196,49 +226,55 @@ impl RegisterFile {
         (self.flags & flag.mask()) != 0
     }
 
+    /// Loads FLAGS from `value`, changing only the bits in `writable` and
+    /// keeping the reserved bits fixed.
+    pub fn load_flags(&mut self, value: u16, writable: u16) {
+        self.flags = (self.flags & !writable) | (value & writable) | FLAGS_RESERVED_SET;
+    }
+
     pub fn get_register_value(&self, reg_access: &RegisterAccess) -> u16 {
         match reg_access.index {
             RegisterIndex::A => {
                 if reg_access.count == 2 {
-                    self.ax as u16
+                    self.ax
                 } else {
                     if reg_access.offset == 0 {
-                        (self.ax & 0x00FF) as u16
+                        self.ax & 0x00FF
                     } else {
-                        (self.ax >> 8) as u16
+                        self.ax >> 8
                     }
                 }
             }
             RegisterIndex::B => {
                 if reg_access.count == 2 {
-                    self.bx as u16
+                    self.bx
                 } else {
                     if reg_access.offset == 0 {
-                        (self.bx & 0x00FF) as u16
+                        self.bx & 0x00FF
                     } else {
-                        (self.bx >> 8) as u16
+                        self.bx >> 8
                     }
                 }
             }
             RegisterIndex::C => {
                 if reg_access.count == 2 {
-                    self.cx as u16
+                    self.cx
                 } else {
                     if reg_access.offset == 0 {
-                        (self.cx & 0x00FF) as u16
+                        self.cx & 0x00FF
                     } else {
-                        (self.cx >> 8) as u16
+                        self.cx >> 8
                     }
                 }
             }
             RegisterIndex::D => {
                 if reg_access.count == 2 {
-                    self.dx as u16
+                    self.dx
                 } else {
                     if reg_access.offset == 0 {
-                        (self.dx & 0x00FF) as u16
+                        self.dx & 0x00FF
                     } else {
-                        (self.dx >> 8) as u16
+                        self.dx >> 8
                     }
                 }
             }
@@ -315,40 +351,690 @@ impl RegisterFile {
     }
 
     pub fn update_ip(&mut self, value: u16) {
-        self.ip = self.ip + value;
+        self.ip = self.ip.wrapping_add(value);
     }
 
     pub fn get_ip(&mut self) -> u16 {
         self.ip
     }
 
-    fn set_flags_add(&mut self, src: u16, dst: u16, res: u16) {
+    /// Mask covering the operand width selected by `InstructionFlag::WIDE`.
+    pub fn width_mask(wide: bool) -> u16 {
+        if wide { 0xFFFF } else { 0x00FF }
+    }
+
+    /// Most significant bit of the operand width.
+    pub fn sign_bit(wide: bool) -> u16 {
+        if wide { 0x8000 } else { 0x0080 }
+    }
+
+    /// Sets ZF, SF and PF from a result that is already truncated to the operand width.
+    fn set_flags_result(&mut self, res: u16, wide: bool) {
         self.set_flag(Flag::Zero, res == 0);
-        self.set_flag(Flag::Sign, (res & 0x8000) != 0);
-        self.set_flag(Flag::Overflow, ((dst ^ res) & (src ^ res) & 0x8000) != 0);
-        self.set_flag(Flag::Parity, (res & 0xFF).count_ones() % 2 == 0);
+        self.set_flag(Flag::Sign, (res & Self::sign_bit(wide)) != 0);
+        self.set_flag(Flag::Parity, (res & 0xFF).count_ones().is_multiple_of(2));
+    }
+
+    fn set_flags_add(&mut self, src: u16, dst: u16, res: u16, wide: bool) {
+        self.set_flags_result(res, wide);
+        self.set_flag(Flag::Overflow, ((dst ^ res) & (src ^ res) & Self::sign_bit(wide)) != 0);
         self.set_flag(Flag::Auxiliary, ((dst ^ src ^ res) & 0x10) != 0);
     }
 
-    fn set_flags_sub(&mut self, src: u16, dst: u16, res: u16) {
-        self.set_flag(Flag::Zero, res == 0);
-        self.set_flag(Flag::Sign, (res & 0x8000) != 0);
-        self.set_flag(Flag::Overflow, ((dst ^ src) & (dst ^ res) & 0x8000) != 0);
-        self.set_flag(Flag::Parity, (res & 0xFF).count_ones() % 2 == 0);
+    fn set_flags_sub(&mut self, src: u16, dst: u16, res: u16, wide: bool) {
+        self.set_flags_result(res, wide);
+        self.set_flag(Flag::Overflow, ((dst ^ src) & (dst ^ res) & Self::sign_bit(wide)) != 0);
         self.set_flag(Flag::Auxiliary, ((dst ^ src ^ res) & 0x10) != 0);
     }
 
-    pub fn alu_add(&mut self, dst: u16, src: u16) -> u16 {
-        let res = dst.wrapping_add(src);
-        self.set_flag(Flag::Carry, (dst as u32 + src as u32) > 0xFFFF);
-        self.set_flags_add(src, dst, res);
+    fn add_with_carry(&mut self, dst: u16, src: u16, carry_in: bool, wide: bool) -> u16 {
+        let mask = Self::width_mask(wide);
+        let (dst, src) = (dst & mask, src & mask);
+        let full = dst as u32 + src as u32 + carry_in as u32;
+        let res = (full as u16) & mask;
+        self.set_flag(Flag::Carry, full > mask as u32);
+        self.set_flags_add(src, dst, res, wide);
+        res
+    }
+
+    fn sub_with_borrow(&mut self, dst: u16, src: u16, borrow_in: bool, wide: bool) -> u16 {
+        let mask = Self::width_mask(wide);
+        let (dst, src) = (dst & mask, src & mask);
+        let res = dst.wrapping_sub(src).wrapping_sub(borrow_in as u16) & mask;
+        self.set_flag(Flag::Carry, (dst as u32) < src as u32 + borrow_in as u32);
+        self.set_flags_sub(src, dst, res, wide);
+        res
+    }
+
+    pub fn alu_add(&mut self, dst: u16, src: u16, wide: bool) -> u16 {
+        self.add_with_carry(dst, src, false, wide)
+    }
+
+    pub fn alu_adc(&mut self, dst: u16, src: u16, wide: bool) -> u16 {
+        let carry = self.get_flag(Flag::Carry);
+        self.add_with_carry(dst, src, carry, wide)
+    }
+
+    pub fn alu_sub(&mut self, dst: u16, src: u16, wide: bool) -> u16 {
+        self.sub_with_borrow(dst, src, false, wide)
+    }
+
+    pub fn alu_sbb(&mut self, dst: u16, src: u16, wide: bool) -> u16 {
+        let borrow = self.get_flag(Flag::Carry);
+        self.sub_with_borrow(dst, src, borrow, wide)
+    }
+
+    /// INC leaves CF untouched.
+    pub fn alu_inc(&mut self, dst: u16, wide: bool) -> u16 {
+        let carry = self.get_flag(Flag::Carry);
+        let res = self.add_with_carry(dst, 1, false, wide);
+        self.set_flag(Flag::Carry, carry);
+        res
+    }
+
+    /// DEC leaves CF untouched.
+    pub fn alu_dec(&mut self, dst: u16, wide: bool) -> u16 {
+        let carry = self.get_flag(Flag::Carry);
+        let res = self.sub_with_borrow(dst, 1, false, wide);
+        self.set_flag(Flag::Carry, carry);
+        res
+    }
+
+    /// NEG is a subtraction from zero, so CF ends up set for any non-zero operand.
+    pub fn alu_neg(&mut self, dst: u16, wide: bool) -> u16 {
+        self.sub_with_borrow(0, dst, false, wide)
+    }
+
+    /// AND, OR, XOR and TEST clear CF and OF and set SF, ZF and PF from the result.
+    fn set_flags_logic(&mut self, res: u16, wide: bool) {
+        self.set_flags_result(res, wide);
+        self.set_flag(Flag::Carry, false);
+        self.set_flag(Flag::Overflow, false);
+        self.set_flag(Flag::Auxiliary, false);
+    }
+
+    pub fn alu_and(&mut self, dst: u16, src: u16, wide: bool) -> u16 {
+        let res = dst & src & Self::width_mask(wide);
+        self.set_flags_logic(res, wide);
+        res
+    }
+
+    pub fn alu_or(&mut self, dst: u16, src: u16, wide: bool) -> u16 {
+        let res = (dst | src) & Self::width_mask(wide);
+        self.set_flags_logic(res, wide);
         res
     }
 
-    pub fn alu_sub(&mut self, dst: u16, src: u16) -> u16 {
-        let res = dst.wrapping_sub(src);
-        self.set_flag(Flag::Carry, dst < src);
-        self.set_flags_sub(src, dst, res);
+    pub fn alu_xor(&mut self, dst: u16, src: u16, wide: bool) -> u16 {
+        let res = (dst ^ src) & Self::width_mask(wide);
+        self.set_flags_logic(res, wide);
+        res
+    }
+
+    /// Shifts and rotates one bit at a time, as the 8086 does for counts above
+    /// one. CF receives the last bit shifted out. OF follows the single-bit rules
+    /// and for longer counts reflects the final step. A zero count leaves the
+    /// flags untouched.
+    pub fn alu_shift(&mut self, op: OperationType, dst: u16, count: u8, wide: bool) -> u16 {
+        let mask = Self::width_mask(wide);
+        let msb = Self::sign_bit(wide);
+        let mut res = dst & mask;
+
+        if count == 0 {
+            return res;
+        }
+
+        let mut carry = self.get_flag(Flag::Carry);
+        for _ in 0..count {
+            match op {
+                OperationType::Shl | OperationType::Sal => {
+                    carry = (res & msb) != 0;
+                    res = (res << 1) & mask;
+                }
+                OperationType::Shr => {
+                    carry = (res & 1) != 0;
+                    res >>= 1;
+                }
+                OperationType::Sar => {
+                    carry = (res & 1) != 0;
+                    res = (res >> 1) | (res & msb);
+                }
+                OperationType::Rol => {
+                    carry = (res & msb) != 0;
+                    res = ((res << 1) | carry as u16) & mask;
+                }
+                OperationType::Ror => {
+                    carry = (res & 1) != 0;
+                    res = (res >> 1) | if carry { msb } else { 0 };
+                }
+                OperationType::Rcl => {
+                    let out = (res & msb) != 0;
+                    res = ((res << 1) | carry as u16) & mask;
+                    carry = out;
+                }
+                OperationType::Rcr => {
+                    let out = (res & 1) != 0;
+                    res = (res >> 1) | if carry { msb } else { 0 };
+                    carry = out;
+                }
+                _ => {}
+            }
+        }
+
+        self.set_flag(Flag::Carry, carry);
+
+        // Left shifts overflow when the new sign differs from the carry, right
+        // shifts when the top two bits of the result differ.
+        let overflow = match op {
+            OperationType::Shl | OperationType::Sal | OperationType::Rol | OperationType::Rcl => {
+                ((res & msb) != 0) != carry
+            }
+            _ => ((res ^ (res << 1)) & msb) != 0,
+        };
+        self.set_flag(Flag::Overflow, overflow);
+
+        if matches!(op, OperationType::Shl | OperationType::Sal | OperationType::Shr | OperationType::Sar) {
+            self.set_flags_result(res, wide);
+        }
+
         res
     }
+
+    /// Unsigned multiply of AL or AX by `src` into AX or DX:AX. CF and OF are
+    /// set when the upper half of the product is non-zero.
+    pub fn alu_mul(&mut self, src: u16, wide: bool) {
+        let high = if wide {
+            let product = self.ax as u32 * src as u32;
+            self.ax = product as u16;
+            self.dx = (product >> 16) as u16;
+            self.dx
+        } else {
+            self.ax = (self.ax & 0xFF) * (src & 0xFF);
+            self.ax >> 8
+        };
+
+        self.set_flag(Flag::Carry, high != 0);
+        self.set_flag(Flag::Overflow, high != 0);
+    }
+
+    /// Signed multiply of AL or AX by `src` into AX or DX:AX. CF and OF are
+    /// set when the upper half is more than a sign extension of the lower half.
+    pub fn alu_imul(&mut self, src: u16, wide: bool) {
+        let extends = if wide {
+            let product = (self.ax as i16 as i32) * (src as i16 as i32);
+            self.ax = product as u16;
+            self.dx = (product >> 16) as u16;
+            product == product as i16 as i32
+        } else {
+            let product = (self.ax as i8 as i16) * (src as i8 as i16);
+            self.ax = product as u16;
+            product == product as i8 as i16
+        };
+
+        self.set_flag(Flag::Carry, !extends);
+        self.set_flag(Flag::Overflow, !extends);
+    }
+
+    /// Unsigned divide of AX or DX:AX by `src`, leaving the quotient in AL or AX
+    /// and the remainder in AH or DX. Returns false without touching any
+    /// register when the divisor is zero or the quotient does not fit.
+    pub fn alu_div(&mut self, src: u16, wide: bool) -> bool {
+        if wide {
+            let dividend = ((self.dx as u32) << 16) | self.ax as u32;
+            let divisor = src as u32;
+            if divisor == 0 || dividend / divisor > 0xFFFF {
+                return false;
+            }
+            self.ax = (dividend / divisor) as u16;
+            self.dx = (dividend % divisor) as u16;
+        } else {
+            let dividend = self.ax;
+            let divisor = src & 0xFF;
+            if divisor == 0 || dividend / divisor > 0xFF {
+                return false;
+            }
+            self.ax = ((dividend % divisor) << 8) | (dividend / divisor);
+        }
+        true
+    }
+
+    /// Signed divide of AX or DX:AX by `src`. The quotient truncates toward zero
+    /// and the remainder takes the sign of the dividend. The 8086 rejects the
+    /// most negative quotient (-128 or -32768) as an overflow.
+    pub fn alu_idiv(&mut self, src: u16, wide: bool) -> bool {
+        let (dividend, divisor, limit) = if wide {
+            let dividend = (((self.dx as u32) << 16) | self.ax as u32) as i32 as i64;
+            (dividend, src as i16 as i64, i16::MAX as i64)
+        } else {
+            (self.ax as i16 as i64, src as u8 as i8 as i64, i8::MAX as i64)
+        };
+
+        if divisor == 0 {
+            return false;
+        }
+
+        let quotient = dividend / divisor;
+        let remainder = dividend % divisor;
+        if quotient > limit || quotient < -limit {
+            return false;
+        }
+
+        if wide {
+            self.ax = quotient as u16;
+            self.dx = remainder as u16;
+        } else {
+            self.ax = ((remainder as u16 & 0xFF) << 8) | (quotient as u16 & 0xFF);
+        }
+        true
+    }
+
+    /// ASCII adjust after addition. The 8086 adds 6 to AL and 1 to AH
+    /// separately, and the flags other than AF and CF describe that add before
+    /// AL is masked to a digit.
+    pub fn alu_aaa(&mut self) {
+        let adjust = (self.ax & 0x0F) > 9 || self.get_flag(Flag::Auxiliary);
+        let al = self.alu_add(self.ax & 0xFF, if adjust { 6 } else { 0 }, false);
+        let ah = (self.ax >> 8).wrapping_add(adjust as u16) & 0xFF;
+        self.ax = (ah << 8) | (al & 0x0F);
+        self.set_flag(Flag::Auxiliary, adjust);
+        self.set_flag(Flag::Carry, adjust);
+    }
+
+    /// ASCII adjust after subtraction, the mirror image of AAA.
+    pub fn alu_aas(&mut self) {
+        let adjust = (self.ax & 0x0F) > 9 || self.get_flag(Flag::Auxiliary);
+        let al = self.alu_sub(self.ax & 0xFF, if adjust { 6 } else { 0 }, false);
+        let ah = (self.ax >> 8).wrapping_sub(adjust as u16) & 0xFF;
+        self.ax = (ah << 8) | (al & 0x0F);
+        self.set_flag(Flag::Auxiliary, adjust);
+        self.set_flag(Flag::Carry, adjust);
+    }
+
+    /// Picks the DAA/DAS correction for AL. With AF set the 8086 tests the
+    /// upper digit against 0x9F rather than 0x99.
+    fn decimal_correction(&self) -> u16 {
+        let al = self.ax & 0xFF;
+        let af = self.get_flag(Flag::Auxiliary);
+        let mut correction = 0;
+        if (al & 0x0F) > 9 || af {
+            correction |= 0x06;
+        }
+        if al > (if af { 0x9F } else { 0x99 }) || self.get_flag(Flag::Carry) {
+            correction |= 0x60;
+        }
+        correction
+    }
+
+    /// Decimal adjust after addition. OF, SF, ZF and PF come from adding the
+    /// correction to AL.
+    pub fn alu_daa(&mut self) {
+        let correction = self.decimal_correction();
+        let al = self.alu_add(self.ax & 0xFF, correction, false);
+        self.ax = (self.ax & 0xFF00) | al;
+        self.set_flag(Flag::Auxiliary, (correction & 0x06) != 0);
+        self.set_flag(Flag::Carry, (correction & 0x60) != 0);
+    }
+
+    /// Decimal adjust after subtraction. A borrow out of the low-digit
+    /// correction also sets CF.
+    pub fn alu_das(&mut self) {
+        let old_al = self.ax & 0xFF;
+        let correction = self.decimal_correction();
+        let al = self.alu_sub(old_al, correction, false);
+        self.ax = (self.ax & 0xFF00) | al;
+        self.set_flag(Flag::Auxiliary, (correction & 0x06) != 0);
+        self.set_flag(
+            Flag::Carry,
+            (correction & 0x60) != 0 || ((correction & 0x06) != 0 && old_al < 6),
+        );
+    }
+
+    /// ASCII adjust after multiply: AH = AL / base, AL = AL % base. SF, ZF and
+    /// PF follow the new AL while OF, AF and CF are cleared. Returns false for a
+    /// zero base, which the 8086 treats as a divide error.
+    pub fn alu_aam(&mut self, base: u8) -> bool {
+        if base == 0 {
+            return false;
+        }
+
+        let al = (self.ax & 0xFF) as u8;
+        let (quotient, remainder) = (al / base, al % base);
+        self.ax = ((quotient as u16) << 8) | remainder as u16;
+        self.set_flags_logic(remainder as u16, false);
+        true
+    }
+
+    /// ASCII adjust before division: AL = AH * base + AL, AH = 0. The final
+    /// addition goes through the ALU, so every arithmetic flag reflects it.
+    pub fn alu_aad(&mut self, base: u8) {
+        let product = ((self.ax >> 8) * base as u16) & 0xFF;
+        self.ax = self.alu_add(self.ax & 0xFF, product, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CF: u16 = 1 << Flag::Carry as u16;
+    const PF: u16 = 1 << Flag::Parity as u16;
+    const AF: u16 = 1 << Flag::Auxiliary as u16;
+    const ZF: u16 = 1 << Flag::Zero as u16;
+    const SF: u16 = 1 << Flag::Sign as u16;
+    const OF: u16 = 1 << Flag::Overflow as u16;
+    const ARITHMETIC: u16 = CF | PF | AF | ZF | SF | OF;
+
+    /// Runs `op` on each `(ax, flags in, ax out, flags out)` row and checks
+    /// AX and all six arithmetic flags, undefined ones included.
+    fn check(name: &str, op: impl Fn(&mut RegisterFile), cases: &[(u16, u16, u16, u16)]) {
+        for &(ax, flags_in, ax_out, flags_out) in cases {
+            let mut registers = RegisterFile::new();
+            registers.ax = ax;
+            registers.flags = (registers.flags & !ARITHMETIC) | flags_in;
+            op(&mut registers);
+            assert_eq!(
+                (registers.ax, registers.flags & ARITHMETIC),
+                (ax_out, flags_out),
+                "{} with AX={:04X} flags={:03X}",
+                name,
+                ax,
+                flags_in
+            );
+        }
+    }
+
+    /// A binary ALU operation applied to AX and `src`, writing AX back.
+    fn on_ax(op: fn(&mut RegisterFile, u16, u16, bool) -> u16, src: u16, wide: bool) -> impl Fn(&mut RegisterFile) {
+        move |registers| registers.ax = op(registers, registers.ax, src, wide)
+    }
+
+    #[test]
+    fn add_and_adc() {
+        check("add al, 1", on_ax(RegisterFile::alu_add, 1, false), &[
+            (0x007F, 0, 0x0080, OF | SF | AF),
+            (0x00FF, 0, 0x0000, CF | ZF | AF | PF),
+            (0x000E, CF, 0x000F, PF),
+        ]);
+        check("add al, 80h", on_ax(RegisterFile::alu_add, 0x80, false), &[(0x0080, 0, 0x0000, CF | OF | ZF | PF)]);
+        check("add ax, 1", on_ax(RegisterFile::alu_add, 1, true), &[
+            (0x7FFF, 0, 0x8000, OF | SF | AF | PF),
+            (0xFFFF, 0, 0x0000, CF | ZF | AF | PF),
+            // Only bit 7 sets CF for a byte, bit 15 for a word
+            (0x00FF, 0, 0x0100, AF | PF),
+        ]);
+        check("adc al, 0", on_ax(RegisterFile::alu_adc, 0, false), &[
+            (0x00FF, CF, 0x0000, CF | ZF | AF | PF),
+            (0x007F, CF, 0x0080, OF | SF | AF),
+            (0x007F, 0, 0x007F, 0),
+        ]);
+        check("adc al, 0FFh", on_ax(RegisterFile::alu_adc, 0xFF, false), &[(0x00FF, CF, 0x00FF, CF | AF | SF | PF)]);
+    }
+
+    #[test]
+    fn sub_sbb_and_cmp() {
+        // CMP is SUB without the write back, so both share these rows
+        check("sub al, 1", on_ax(RegisterFile::alu_sub, 1, false), &[
+            (0x0080, 0, 0x007F, OF | AF),
+            (0x0000, 0, 0x00FF, CF | AF | SF | PF),
+            // The borrow out of the low nibble sets AF alone
+            (0x0010, 0, 0x000F, AF | PF),
+            (0x0001, CF, 0x0000, ZF | PF),
+        ]);
+        check("sub ax, 1", on_ax(RegisterFile::alu_sub, 1, true), &[
+            (0x8000, 0, 0x7FFF, OF | AF | PF),
+            (0x0100, 0, 0x00FF, AF | PF),
+        ]);
+        check("sbb al, 0", on_ax(RegisterFile::alu_sbb, 0, false), &[
+            (0x0000, CF, 0x00FF, CF | AF | SF | PF),
+            (0x0080, CF, 0x007F, OF | AF),
+            (0x0080, 0, 0x0080, SF),
+        ]);
+        check("sbb al, 4", on_ax(RegisterFile::alu_sbb, 4, false), &[(0x0005, CF, 0x0000, ZF | PF)]);
+    }
+
+    #[test]
+    fn inc_dec_and_neg() {
+        let inc = |wide| move |registers: &mut RegisterFile| registers.ax = registers.alu_inc(registers.ax, wide);
+        let dec = |wide| move |registers: &mut RegisterFile| registers.ax = registers.alu_dec(registers.ax, wide);
+        let neg = |wide| move |registers: &mut RegisterFile| registers.ax = registers.alu_neg(registers.ax, wide);
+
+        // INC and DEC pass CF through whatever the result
+        check("inc al", inc(false), &[
+            (0x00FF, 0, 0x0000, ZF | AF | PF),
+            (0x00FF, CF, 0x0000, CF | ZF | AF | PF),
+            (0x007F, CF, 0x0080, CF | OF | SF | AF),
+        ]);
+        check("inc ax", inc(true), &[(0x7FFF, 0, 0x8000, OF | SF | AF | PF)]);
+        check("dec al", dec(false), &[
+            (0x0000, 0, 0x00FF, AF | SF | PF),
+            (0x0080, CF, 0x007F, CF | OF | AF),
+            (0x0001, CF, 0x0000, CF | ZF | PF),
+        ]);
+        check("neg al", neg(false), &[
+            (0x0000, CF, 0x0000, ZF | PF),
+            (0x0001, 0, 0x00FF, CF | AF | SF | PF),
+            (0x0080, 0, 0x0080, CF | OF | SF),
+        ]);
+        check("neg ax", neg(true), &[(0x8000, 0, 0x8000, CF | OF | SF | PF)]);
+    }
+
+    fn shift(op: OperationType, count: u8, wide: bool) -> impl Fn(&mut RegisterFile) {
+        move |registers| registers.ax = registers.alu_shift(op, registers.ax, count, wide)
+    }
+
+    #[test]
+    fn single_bit_shifts_and_rotates() {
+        use OperationType::*;
+
+        check("shl al, 1", shift(Shl, 1, false), &[
+            (0x0080, 0, 0x0000, CF | OF | ZF | PF),
+            (0x0040, 0, 0x0080, OF | SF),
+            (0x00C0, 0, 0x0080, CF | SF),
+        ]);
+        check("shl ax, 1", shift(Shl, 1, true), &[(0x4000, 0, 0x8000, OF | SF | PF)]);
+        // OF is the sign of the operand for SHR and always clear for SAR
+        check("shr al, 1", shift(Shr, 1, false), &[(0x0081, 0, 0x0040, CF | OF)]);
+        check("sar al, 1", shift(Sar, 1, false), &[(0x0081, 0, 0x00C0, CF | SF | PF)]);
+
+        // Rotates only write CF and OF
+        check("rol al, 1", shift(Rol, 1, false), &[
+            (0x0080, 0, 0x0001, CF | OF),
+            (0x0001, ZF | SF, 0x0002, ZF | SF),
+        ]);
+        check("ror al, 1", shift(Ror, 1, false), &[(0x0001, 0, 0x0080, CF | OF)]);
+        check("rcl al, 1", shift(Rcl, 1, false), &[
+            (0x0000, CF, 0x0001, 0),
+            (0x0080, CF, 0x0001, CF | OF),
+        ]);
+        check("rcr al, 1", shift(Rcr, 1, false), &[
+            (0x0001, CF, 0x0080, CF | OF),
+            (0x0000, CF, 0x0080, OF),
+        ]);
+    }
+
+    #[test]
+    fn zero_counts_leave_the_flags_alone() {
+        use OperationType::*;
+
+        for op in [Shl, Shr, Sar, Rol, Ror, Rcl, Rcr] {
+            check("shift by 0", shift(op, 0, false), &[
+                (0x0080, CF | OF | ZF, 0x0080, CF | OF | ZF),
+                (0x0001, SF | PF, 0x0001, SF | PF),
+            ]);
+        }
+    }
+
+    #[test]
+    fn multi_bit_counts_carry_out_the_last_bit() {
+        use OperationType::*;
+
+        // (op, wide, value, CF in, count, result, CF out); OF is undefined
+        let cases = [
+            (Shr, false, 0x0C, false, 3, 0x01, true),
+            (Shr, false, 0x0C, true, 2, 0x03, false),
+            (Shl, false, 0x18, false, 4, 0x80, true),
+            (Shl, true, 0x0180, false, 8, 0x8000, true),
+            (Sar, false, 0x80, false, 8, 0xFF, true),
+            (Rol, true, 0x8001, false, 4, 0x0018, false),
+            // Through carry a byte takes 9 steps and a word 17 to come round
+            (Rcl, false, 0x5A, true, 9, 0x5A, true),
+            (Rcr, true, 0x1234, false, 17, 0x1234, false),
+            (Rcl, false, 0x80, false, 2, 0x01, false),
+        ];
+        for (op, wide, value, carry, count, result, carry_out) in cases {
+            let mut registers = RegisterFile::new();
+            registers.set_flag(Flag::Carry, carry);
+            assert_eq!(
+                (registers.alu_shift(op, value, count, wide), registers.get_flag(Flag::Carry)),
+                (result, carry_out),
+                "{op:?} {value:04X} by {count}"
+            );
+        }
+    }
+
+    #[test]
+    fn multiply_flags_a_significant_high_half() {
+        // (wide, AX, src, AX out, DX out, CF and OF)
+        let cases = [
+            (false, 0x0010, 0x10, 0x0100, 0, true),
+            (false, 0x000F, 0x11, 0x00FF, 0, false),
+            (true, 0x8000, 0x0002, 0x0000, 0x0001, true),
+            (true, 0x00FF, 0x0100, 0xFF00, 0x0000, false),
+        ];
+        for (wide, ax, src, ax_out, dx_out, carry) in cases {
+            let mut registers = RegisterFile::new();
+            registers.ax = ax;
+            registers.alu_mul(src, wide);
+            assert_eq!(
+                (registers.ax, registers.dx, registers.get_flag(Flag::Carry), registers.get_flag(Flag::Overflow)),
+                (ax_out, dx_out, carry, carry),
+                "mul {ax:04X} by {src:04X}"
+            );
+        }
+
+        // For IMUL the high half is insignificant if it only extends the sign
+        let cases = [
+            (false, 0x00FF, 0x7F, 0xFF81, 0, false),
+            (false, 0x0040, 0x02, 0x0080, 0, true),
+            (false, 0x0080, 0xFF, 0x0080, 0, true),
+            (true, 0xFFFF, 0x7FFF, 0x8001, 0xFFFF, false),
+            (true, 0x4000, 0x0002, 0x8000, 0x0000, true),
+        ];
+        for (wide, ax, src, ax_out, dx_out, carry) in cases {
+            let mut registers = RegisterFile::new();
+            registers.ax = ax;
+            registers.alu_imul(src, wide);
+            assert_eq!(
+                (registers.ax, registers.dx, registers.get_flag(Flag::Carry), registers.get_flag(Flag::Overflow)),
+                (ax_out, dx_out, carry, carry),
+                "imul {ax:04X} by {src:04X}"
+            );
+        }
+    }
+
+    #[test]
+    fn divide_rejects_zero_divisors_and_quotient_overflow() {
+        // (signed, wide, DX, AX, src, AX and DX out, or None for a divide error)
+        let cases = [
+            (false, false, 0, 0x0100, 0x01, None),
+            (false, false, 0, 0x0100, 0x02, Some((0x0080, 0))),
+            (false, false, 0, 0x0007, 0x00, None),
+            (false, true, 0x0001, 0x0003, 0x0002, Some((0x8001, 0x0001))),
+            (false, true, 0x0002, 0x0000, 0x0002, None),
+            // The quotient truncates and the remainder takes the dividend's sign
+            (true, false, 0, 0xFFF9, 0x02, Some((0xFFFD, 0))),
+            (true, false, 0, 0x007F, 0x01, Some((0x007F, 0))),
+            (true, false, 0, 0xFF81, 0x01, Some((0x0081, 0))),
+            // -128 fits in a byte, but the 8086 raises the divide error for it
+            (true, false, 0, 0xFF80, 0x01, None),
+            (true, false, 0, 0x0080, 0xFF, None),
+            (true, true, 0xFFFF, 0x8001, 0x0001, Some((0x8001, 0))),
+            (true, true, 0xFFFF, 0x8000, 0x0001, None),
+            (true, true, 0x0000, 0x8000, 0xFFFF, None),
+            (true, true, 0x1234, 0x5678, 0x0000, None),
+        ];
+        for (signed, wide, dx, ax, src, expected) in cases {
+            let mut registers = RegisterFile::new();
+            (registers.ax, registers.dx) = (ax, dx);
+            let ok = if signed { registers.alu_idiv(src, wide) } else { registers.alu_div(src, wide) };
+            let result = ok.then_some((registers.ax, registers.dx));
+            assert_eq!(result, expected, "{} {dx:04X}:{ax:04X} by {src:04X}", if signed { "idiv" } else { "div" });
+            if !ok {
+                assert_eq!((registers.ax, registers.dx), (ax, dx), "registers changed by a divide error");
+            }
+        }
+    }
+
+    #[test]
+    fn aaa() {
+        check("aaa", RegisterFile::alu_aaa, &[
+            (0x0009, 0, 0x0009, PF),
+            (0x000A, 0, 0x0100, AF | CF),
+            // Adding 6 overflows into the sign bit before AL is masked
+            (0x007A, 0, 0x0100, CF | AF | SF | OF),
+            (0x00FF, 0, 0x0105, CF | AF | PF),
+            // AH carries out to zero, but ZF describes AL + 6
+            (0xFF9A, 0, 0x0000, CF | AF | SF | PF),
+            (0x0000, AF, 0x0106, CF | AF | PF),
+        ]);
+    }
+
+    #[test]
+    fn aas() {
+        check("aas", RegisterFile::alu_aas, &[
+            (0x0009, 0, 0x0009, PF),
+            (0x000A, 0, 0xFF04, AF | CF),
+            (0x0080, AF, 0xFF0A, CF | AF | OF),
+            (0x0105, AF, 0x000F, CF | AF | SF | PF),
+        ]);
+    }
+
+    #[test]
+    fn daa() {
+        check("daa", RegisterFile::alu_daa, &[
+            (0x129A, 0, 0x1200, CF | AF | ZF | PF),
+            // With AF set the upper digit is only corrected above 0x9F
+            (0x009B, AF, 0x00A1, AF | SF),
+            (0x007A, 0, 0x0080, AF | SF | OF),
+            (0x00FF, 0, 0x0065, CF | AF | PF),
+            (0x0000, CF, 0x0060, CF | PF),
+        ]);
+    }
+
+    #[test]
+    fn das() {
+        check("das", RegisterFile::alu_das, &[
+            (0x009A, 0, 0x0034, CF | AF | OF),
+            // The low-digit correction borrows, which sets CF on its own
+            (0x0003, AF, 0x00FD, CF | AF | SF),
+            (0x00FF, 0, 0x0099, CF | AF | SF | PF),
+            (0x0000, CF, 0x00A0, CF | SF | PF),
+        ]);
+    }
+
+    #[test]
+    fn aam() {
+        let aam = |base| move |registers: &mut RegisterFile| assert!(registers.alu_aam(base));
+        check("aam 10", aam(10), &[
+            (0x00FF, CF | AF | OF, 0x1905, PF),
+            (0x0000, 0, 0x0000, ZF | PF),
+        ]);
+        check("aam 16", aam(16), &[(0x009A, 0, 0x090A, PF)]);
+
+        let mut registers = RegisterFile::new();
+        registers.ax = 0x1234;
+        assert!(!registers.alu_aam(0));
+        assert_eq!(registers.ax, 0x1234);
+    }
+
+    #[test]
+    fn aad() {
+        check("aad", |registers| registers.alu_aad(10), &[
+            (0x0105, 0, 0x000F, PF),
+            (0x0909, 0, 0x0063, AF | PF),
+            // AH * 10 is truncated to a byte before the add carries out
+            (0x19FF, 0, 0x00F9, CF | AF | SF | PF),
+            (0x0C7F, 0, 0x00F7, AF | SF | OF),
+        ]);
+    }
 }
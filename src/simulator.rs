@@ -0,0 +1,440 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::{
+    decoder::{decode_instruction, DecodeError, Instruction, Operand},
+    execution_unit::{execute_instruction, interrupt, UnhandledInterrupt, SINGLE_STEP},
+    fpu::Fpu,
+    instruction_formats::OperationType,
+    memory::{Memory, SegmentedAccess},
+    pic::Pic,
+    pit::{Pit, CPU_CYCLES_PER_PIT_TICK},
+    port_bus::PortBus,
+    register::{Flag, RegisterFile, RegisterIndex},
+    timing::estimate_cycles,
+};
+
+/// Host-side handler for a software interrupt. When registered for a vector it
+/// runs in place of vectoring through the IVT whenever `INT n` executes.
+pub type InterruptHook = Box<dyn FnMut(&mut RegisterFile, &mut Memory)>;
+
+/// Longest stretch HLT waits for an IRQ before giving up: two periods of
+/// the slowest timer setting, so any programmed PIT channel fires in it.
+const MAX_IDLE_PIT_TICKS: u64 = 2 * 0x10000;
+
+/// Why `Simulator::run` returned control to the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// HLT executed with interrupts disabled, or no interrupt arrived to end it.
+    Halted,
+    /// The instruction budget given to `run` was used up.
+    InstructionLimit,
+    /// CS:IP reached a breakpoint. The instruction there has not run yet.
+    Breakpoint { segment: u16, offset: u16 },
+    /// The bytes at CS:IP do not decode to an instruction, for the reason
+    /// `error` gives.
+    InvalidOpcode { segment: u16, offset: u16, error: DecodeError },
+    /// An interrupt with a null IVT entry, raised by an instruction, a CPU
+    /// exception or an IRQ. Nothing was pushed, and CS:IP is left where the
+    /// handler would have returned to.
+    UnhandledInterrupt(u8),
+}
+
+pub struct Simulator {
+    pub memory: Memory,
+    pub registers: RegisterFile,
+    pub ports: PortBus,
+    /// The 8259A on ports 0x20-0x21. Devices raise and lower IRQ lines through it.
+    pub pic: Rc<RefCell<Pic>>,
+    /// The 8253 on ports 0x40-0x43. Channel 0 is wired to IRQ0.
+    pub pit: Rc<RefCell<Pit>>,
+    /// Estimated CPU clocks elapsed since the simulator was created.
+    pub cycles: u64,
+    /// The 8087, if one is fitted. ESC instructions do nothing without it.
+    pub fpu: Option<Fpu>,
+    /// Set by HLT until an interrupt is taken.
+    pub halted: bool,
+    /// Vector of an interrupt the last step found no handler for. Execution
+    /// carries on past it only if the caller steps again.
+    pub unhandled_interrupt: Option<u8>,
+    breakpoints: HashSet<(u16, u16)>,
+    pit_cycles: u64,
+    interrupt_hooks: HashMap<u8, InterruptHook>,
+    interrupt_shadow: bool,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        let pic = Rc::new(RefCell::new(Pic::new()));
+        let mut ports = PortBus::new();
+        ports.map(0x20..=0x21, pic.clone());
+        let pit = Rc::new(RefCell::new(Pit::new()));
+        ports.map(0x40..=0x43, pit.clone());
+        // Channel 0 powers up high; that is not an edge, so IRQ0 first fires a
+        // full period in
+        pic.borrow_mut().seed_line(0, pit.borrow().output(0));
+
+        Self {
+            memory: Memory::new(),
+            registers: RegisterFile::new(),
+            ports,
+            pic,
+            pit,
+            cycles: 0,
+            fpu: None,
+            halted: false,
+            unhandled_interrupt: None,
+            breakpoints: HashSet::new(),
+            pit_cycles: 0,
+            interrupt_hooks: HashMap::new(),
+            interrupt_shadow: false,
+        }
+    }
+
+    /// Routes `INT vector` to `hook` instead of the IVT. Returns the hook it
+    /// replaces, if any.
+    pub fn hook_interrupt<F>(&mut self, vector: u8, hook: F) -> Option<InterruptHook>
+    where
+        F: FnMut(&mut RegisterFile, &mut Memory) + 'static,
+    {
+        self.interrupt_hooks.insert(vector, Box::new(hook))
+    }
+
+    /// Returns `INT vector` to real IVT dispatch.
+    pub fn unhook_interrupt(&mut self, vector: u8) -> Option<InterruptHook> {
+        self.interrupt_hooks.remove(&vector)
+    }
+
+    pub fn is_interrupt_hooked(&self, vector: u8) -> bool {
+        self.interrupt_hooks.contains_key(&vector)
+    }
+
+    /// Makes `run` stop before executing the instruction at `segment:offset`.
+    pub fn add_breakpoint(&mut self, segment: u16, offset: u16) -> bool {
+        self.breakpoints.insert((segment, offset))
+    }
+
+    pub fn remove_breakpoint(&mut self, segment: u16, offset: u16) -> bool {
+        self.breakpoints.remove(&(segment, offset))
+    }
+
+    fn fetch(&self) -> Result<Instruction, DecodeError> {
+        let at = SegmentedAccess {
+            segment_base: self.registers.cs,
+            segment_offset: self.registers.ip,
+        };
+        decode_instruction(&self.memory, &at)
+    }
+
+    /// Decodes and executes the instruction at CS:IP. If the bytes there do
+    /// not decode nothing runs and the `DecodeError` is returned.
+    ///
+    /// While halted nothing is fetched: the step idles for one PIT clock,
+    /// takes a pending IRQ if IF allows it, and returns the HLT.
+    pub fn step(&mut self) -> Result<Instruction, DecodeError> {
+        if self.halted {
+            let address = SegmentedAccess {
+                segment_base: self.registers.cs,
+                segment_offset: self.registers.ip.wrapping_sub(1),
+            };
+            self.idle();
+            return Ok(Instruction {
+                address: address.get_absolute_address(0),
+                size: 1,
+                op: OperationType::Hlt,
+                ..Default::default()
+            });
+        }
+
+        let instruction = self.fetch()?;
+        self.execute(&instruction);
+        Ok(instruction)
+    }
+
+    /// Runs until something stops the CPU or `max_instructions` have
+    /// executed. A breakpoint at the starting CS:IP is stepped over so that a
+    /// run stopped at one can resume. Nothing is printed.
+    pub fn run(&mut self, max_instructions: Option<u64>) -> StopReason {
+        let mut executed = 0;
+        let mut resuming = true;
+
+        loop {
+            if self.halted {
+                if !self.wait_for_interrupt() {
+                    return self.unhandled_interrupt.map_or(StopReason::Halted, StopReason::UnhandledInterrupt);
+                }
+                continue;
+            }
+
+            if max_instructions.is_some_and(|max| executed >= max) {
+                return StopReason::InstructionLimit;
+            }
+
+            let (segment, offset) = (self.registers.cs, self.registers.ip);
+            if !resuming && self.breakpoints.contains(&(segment, offset)) {
+                return StopReason::Breakpoint { segment, offset };
+            }
+            resuming = false;
+
+            let instruction = match self.fetch() {
+                Ok(instruction) => instruction,
+                Err(error) => return StopReason::InvalidOpcode { segment, offset, error },
+            };
+            self.execute(&instruction);
+            executed += 1;
+            if let Some(vector) = self.unhandled_interrupt {
+                return StopReason::UnhandledInterrupt(vector);
+            }
+        }
+    }
+
+    /// Idles while halted until an IRQ is taken. Returns false if IF is clear,
+    /// nothing arrived within the longest timer period or the IRQ had no handler.
+    pub fn wait_for_interrupt(&mut self) -> bool {
+        if !self.registers.get_flag(Flag::Interrupt) {
+            return false;
+        }
+
+        for _ in 0..MAX_IDLE_PIT_TICKS {
+            if self.idle() {
+                return true;
+            }
+            if self.unhandled_interrupt.is_some() {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn idle(&mut self) -> bool {
+        self.advance_cycles(CPU_CYCLES_PER_PIT_TICK);
+        self.service_hardware_interrupt()
+    }
+
+    /// Executes `instruction` as though it had just been fetched from CS:IP.
+    /// This is `step` without the fetch, for callers that decode for
+    /// themselves. An interrupt it finds no handler for is recorded in
+    /// `unhandled_interrupt`.
+    pub fn execute(&mut self, instruction: &Instruction) {
+        self.registers.update_ip(instruction.size as u16);
+        let cx = self.registers.cx;
+
+        let hook = match (instruction.op, instruction.operands[0]) {
+            (OperationType::Int, Operand::Immediate(vector)) => self.interrupt_hooks.get_mut(&(vector as u8)),
+            _ => None,
+        };
+
+        let raised = match hook {
+            Some(hook) => {
+                // The hook stands in for the handler, not the INT, so a single
+                // step still traps once it returns
+                let trap = self.registers.get_flag(Flag::Trap);
+                hook(&mut self.registers, &mut self.memory);
+                if trap {
+                    interrupt(&mut self.memory, &mut self.registers, SINGLE_STEP)
+                } else {
+                    Ok(())
+                }
+            }
+            None => execute_instruction(
+                instruction,
+                &mut self.memory,
+                &mut self.registers,
+                &mut self.ports,
+                self.fpu.as_mut(),
+            ),
+        };
+        self.unhandled_interrupt = raised.err().map(|UnhandledInterrupt(vector)| vector);
+
+        self.advance_cycles(estimate_cycles(instruction, cx.wrapping_sub(self.registers.cx)));
+        self.halted = instruction.op == OperationType::Hlt;
+
+        // The 8086 holds off interrupts after STI and after a load of SS so
+        // that the following SP load completes the stack switch
+        let is_ss = |operand: &Operand| matches!(operand, Operand::Register(reg) if reg.index == RegisterIndex::SS);
+        let writes_ss = match instruction.op {
+            OperationType::Mov => is_ss(&instruction.operands[0]),
+            OperationType::Pop => instruction.operands.iter().any(is_ss),
+            _ => false,
+        };
+        self.interrupt_shadow = writes_ss || instruction.op == OperationType::Sti;
+
+        if !self.interrupt_shadow && self.unhandled_interrupt.is_none() {
+            self.service_hardware_interrupt();
+        }
+    }
+
+    /// Advances the clock by `cycles` CPU clocks, running the PIT alongside
+    /// and passing its channel 0 output on to IRQ0.
+    pub fn advance_cycles(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        self.pit_cycles += cycles;
+        let ticks = self.pit_cycles / CPU_CYCLES_PER_PIT_TICK;
+        self.pit_cycles %= CPU_CYCLES_PER_PIT_TICK;
+
+        let mut pit = self.pit.borrow_mut();
+        pit.advance(ticks);
+
+        // A pulse shorter than the step still latches in the PIC
+        let mut pic = self.pic.borrow_mut();
+        if pit.take_rising_edge(0) {
+            pic.lower_irq(0);
+            pic.raise_irq(0);
+        }
+        if pit.output(0) {
+            pic.raise_irq(0);
+        } else {
+            pic.lower_irq(0);
+        }
+    }
+
+    /// Dispatches the highest priority pending IRQ through the IVT if IF is
+    /// set. Returns whether an interrupt was taken.
+    pub fn service_hardware_interrupt(&mut self) -> bool {
+        if !self.registers.get_flag(Flag::Interrupt) {
+            return false;
+        }
+
+        let Some(vector) = self.pic.borrow_mut().acknowledge() else {
+            return false;
+        };
+        match interrupt(&mut self.memory, &mut self.registers, vector) {
+            Ok(()) => {
+                self.halted = false;
+                true
+            }
+            Err(UnhandledInterrupt(vector)) => {
+                self.unhandled_interrupt = Some(vector);
+                false
+            }
+        }
+    }
+}
+
+/// Fixtures shared by the tests of every module that runs code.
+#[cfg(test)]
+impl Simulator {
+    /// Segment `with_code` loads programs into.
+    pub(crate) const TEST_CODE_SEGMENT: u16 = 0x100;
+
+    /// A simulator with `code` at 0100:0000 and the stack at 0000:1000.
+    pub(crate) fn with_code(code: &[u8]) -> Self {
+        let mut simulator = Self::new();
+        simulator.load((Self::TEST_CODE_SEGMENT as u32) << 4, code);
+        simulator.registers.cs = Self::TEST_CODE_SEGMENT;
+        simulator.registers.sp = 0x1000;
+        simulator
+    }
+
+    /// Copies `bytes` to memory from physical `address` on.
+    pub(crate) fn load(&mut self, address: u32, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory.write(address + i as u32, *byte);
+        }
+    }
+
+    /// The little-endian word at physical `address`.
+    pub(crate) fn read_word(&self, address: u32) -> u16 {
+        u16::from_le_bytes([self.memory.read(address), self.memory.read(address + 1)])
+    }
+
+    /// Points the IVT entry for `vector` at `segment:offset`.
+    pub(crate) fn set_vector(&mut self, vector: u8, segment: u16, offset: u16) {
+        let entry = vector as u32 * 4;
+        self.load(entry, &offset.to_le_bytes());
+        self.load(entry + 2, &segment.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::DecodeErrorReason;
+
+    const CODE_SEGMENT: u16 = Simulator::TEST_CODE_SEGMENT;
+
+    #[test]
+    fn irq0_fires_once_per_timer_period() {
+        // sti; jmp $
+        let mut simulator = Simulator::with_code(&[0xFB, 0xEB, 0xFE]);
+        // inc word [0500h]; mov al, 20h; out 20h, al; iret
+        simulator.load(0x2000, &[0xFF, 0x06, 0x00, 0x05, 0xB0, 0x20, 0xE6, 0x20, 0xCF]);
+        simulator.set_vector(0x08, 0x200, 0);
+
+        let period = 0x10000 * CPU_CYCLES_PER_PIT_TICK;
+        let count = |simulator: &Simulator| simulator.read_word(0x500);
+        let mut first = None;
+        while simulator.cycles < 3 * period + period / 2 {
+            simulator.step().unwrap();
+            if first.is_none() && count(&simulator) != 0 {
+                first = Some(simulator.cycles);
+            }
+        }
+
+        assert_eq!(count(&simulator), 3);
+        assert!(first.unwrap() >= period, "first IRQ0 after {} cycles", first.unwrap());
+    }
+
+    #[test]
+    fn null_vectors_stop_the_run_whatever_raised_them() {
+        // div bl with BL = 0
+        let mut simulator = Simulator::with_code(&[0xF6, 0xF3]);
+        assert_eq!(simulator.run(None), StopReason::UnhandledInterrupt(0));
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (CODE_SEGMENT, 2));
+        assert_eq!(simulator.registers.sp, 0x1000);
+
+        // nop under TF
+        let mut simulator = Simulator::with_code(&[0x90]);
+        simulator.registers.set_flag(Flag::Trap, true);
+        assert_eq!(simulator.run(None), StopReason::UnhandledInterrupt(1));
+
+        // int 21h
+        let mut simulator = Simulator::with_code(&[0xCD, 0x21]);
+        assert_eq!(simulator.run(None), StopReason::UnhandledInterrupt(0x21));
+
+        // sti; jmp $ with IRQ0 unhandled
+        let mut simulator = Simulator::with_code(&[0xFB, 0xEB, 0xFE]);
+        assert_eq!(simulator.run(None), StopReason::UnhandledInterrupt(0x08));
+
+        // sti; hlt with IRQ0 unhandled
+        let mut simulator = Simulator::with_code(&[0xFB, 0xF4]);
+        assert_eq!(simulator.run(None), StopReason::UnhandledInterrupt(0x08));
+    }
+
+    #[test]
+    fn hooked_int_still_single_steps() {
+        // int 21h
+        let mut simulator = Simulator::with_code(&[0xCD, 0x21]);
+        simulator.set_vector(SINGLE_STEP, 0x200, 0x0010);
+        simulator.registers.set_flag(Flag::Trap, true);
+        simulator.hook_interrupt(0x21, |registers, _| registers.ax = 0x4C00);
+
+        simulator.step().unwrap();
+
+        assert_eq!(simulator.registers.ax, 0x4C00);
+        assert_eq!((simulator.registers.cs, simulator.registers.ip), (0x200, 0x0010));
+        assert!(!simulator.registers.get_flag(Flag::Trap));
+        // The trap frame returns to the instruction after the INT
+        assert_eq!(simulator.read_word(simulator.registers.sp as u32), 0x0002);
+    }
+
+    #[test]
+    fn invalid_opcodes_report_the_decode_error() {
+        // nop; then an opcode the 8086 does not define
+        let mut simulator = Simulator::with_code(&[0x90, 0xF1]);
+        let StopReason::InvalidOpcode { segment, offset, error } = simulator.run(None) else {
+            panic!("expected an invalid opcode");
+        };
+        assert_eq!((segment, offset), (CODE_SEGMENT, 1));
+        assert_eq!(error.address, ((CODE_SEGMENT as u32) << 4) + 1);
+        assert_eq!(error.bytes, [0xF1]);
+        assert_eq!(error.reason, DecodeErrorReason::UnknownOpcode);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{
+    decoder::{Instruction, Operand},
+    instruction_formats::OperationType,
+};
+
+/// Approximate 8086 clock count for `instruction`, taken from the base
+/// timings in the Intel manual with a flat effective-address cost for memory
+/// operands. Bus wait states and the prefetch queue are not modelled; this is
+/// only meant to pace the timer. `iterations` is the number of passes a
+/// repeated string instruction made.
+pub fn estimate_cycles(instruction: &Instruction, iterations: u16) -> u64 {
+    use OperationType::*;
+
+    let memory = instruction.operands.iter().any(|operand| matches!(operand, Operand::Memory(_)));
+    let immediate = instruction.operands.iter().any(|operand| matches!(operand, Operand::Immediate(_)));
+    let ea: u64 = if memory { 9 } else { 0 };
+    let prefixes = &instruction.prefixes;
+    let prefix_count = prefixes.lock as u64 + prefixes.rep.is_some() as u64 + prefixes.segment.is_some() as u64;
+
+    let base: u64 = match instruction.op {
+        None => 0,
+        // Prefixes never reach here as instructions of their own
+        Lock | Rep | Segment => 0,
+        Mov if memory => 10 + ea,
+        Mov if immediate => 4,
+        Mov => 2,
+        Add | Adc | Sub | Sbb | And | Or | Xor | Cmp | Test if memory => 16 + ea,
+        Add | Adc | Sub | Sbb | And | Or | Xor | Cmp | Test if immediate => 4,
+        Add | Adc | Sub | Sbb | And | Or | Xor | Cmp | Test => 3,
+        Inc | Dec | Neg | Not if memory => 15 + ea,
+        Inc | Dec | Neg | Not => 3,
+        Shl | Sal | Shr | Sar | Rol | Ror | Rcl | Rcr => 8 + ea,
+        Mul | Imul => 118 + ea,
+        Div | Idiv => 150 + ea,
+        Aam => 83,
+        Aad => 60,
+        Aaa | Aas | Daa | Das => 4,
+        Cbw | Cwd => 2,
+        Xchg => 4 + ea,
+        Xlat => 11,
+        Lea => 2 + ea,
+        Lds | Les => 16 + ea,
+        Lahf | Sahf => 4,
+        Push | Pushf => 11 + ea,
+        Pop | Popf => 8 + ea,
+        In | Out => 10,
+        Movs => 18,
+        Cmps => 22,
+        Scas => 15,
+        Lods => 12,
+        Stos => 11,
+        Call => 19 + ea,
+        Jmp => 15 + ea,
+        Ret => 16,
+        Retf => 26,
+        Je | Jl | Jle | Jb | Jbe | Jp | Jo | Js | Jne | Jnl | Jg | Jnb | Ja | Jnp | Jno | Jns => 8,
+        Loop | Loopz | Loopnz | Jcxz => 9,
+        Int | Int3 | Into => 51,
+        Iret => 24,
+        Clc | Cmc | Stc | Cld | Std | Cli | Sti | Hlt => 2,
+        Wait => 3,
+        Esc => 2 + ea,
+    };
+
+    let cycles = match instruction.op {
+        Movs | Cmps | Scas | Lods | Stos if iterations > 0 => 9 + base * iterations as u64,
+        _ => base,
+    };
+    cycles + 2 * prefix_count
+}